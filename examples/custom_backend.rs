@@ -0,0 +1,108 @@
+//! A backend that writes spans and events to stderr as lines of text,
+//! showing how to plug a custom sink into the layer.
+
+use std::{pin::Pin, time::SystemTime};
+
+use tracing::{event, span, Level};
+use tracing_etw::{EventRecord, EventWriter, Fields, LayerBuilder, SpanRecord, ValueTypes};
+use tracing_subscriber::prelude::*;
+
+struct StderrBackend {
+    max_level: u8,
+}
+
+fn format_fields(fields: Fields<'_>) -> String {
+    let mut out = String::new();
+    for f in fields {
+        let value = match f.value {
+            ValueTypes::U64(u) => u.to_string(),
+            ValueTypes::I64(i) => i.to_string(),
+            ValueTypes::U128(u) => u.to_string(),
+            ValueTypes::I128(i) => i.to_string(),
+            ValueTypes::F64(d) => d.to_string(),
+            ValueTypes::Bool(b) => b.to_string(),
+            ValueTypes::Str(s) => format!("{:?}", s),
+            ValueTypes::Char(c) => format!("{:?}", c),
+            ValueTypes::Error(e) => {
+                let mut s = format!("{:?}", e.message);
+                for source in &e.sources {
+                    s.push_str(&format!(" <- {:?}", source));
+                }
+                s
+            }
+            _ => continue,
+        };
+        out.push_str(&format!(" {}={}", f.field_name, value));
+    }
+    out
+}
+
+fn format_id(id: &[u8; 16]) -> String {
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl EventWriter for StderrBackend {
+    fn enabled(&self, level: u8, _keyword: u64) -> bool {
+        level <= self.max_level
+    }
+
+    fn supports_enable_callback() -> bool {
+        // The level is fixed at construction time.
+        true
+    }
+
+    fn span_start(self: Pin<&Self>, span: &SpanRecord<'_>, _timestamp: SystemTime) {
+        eprintln!(
+            "start {} [{}]{}",
+            span.name,
+            format_id(span.activity_id),
+            format_fields(span.fields)
+        );
+    }
+
+    fn span_stop(
+        self: Pin<&Self>,
+        span: &SpanRecord<'_>,
+        start_stop_times: (SystemTime, SystemTime),
+    ) {
+        let elapsed = start_stop_times
+            .1
+            .duration_since(start_stop_times.0)
+            .unwrap_or_default();
+        eprintln!(
+            "stop  {} [{}] after {:?}{}",
+            span.name,
+            format_id(span.activity_id),
+            elapsed,
+            format_fields(span.fields)
+        );
+    }
+
+    fn write_record(self: Pin<&Self>, event: &EventRecord<'_>) {
+        eprintln!(
+            "event {} [{}] level={}{}",
+            event.name,
+            event.activity_id.map(format_id).unwrap_or_default(),
+            event.level,
+            format_fields(event.fields)
+        );
+    }
+}
+
+fn main() {
+    let backend = StderrBackend { max_level: 5 };
+
+    tracing_subscriber::registry()
+        .with(LayerBuilder::with_backend("stderr_example", backend).build())
+        .init();
+
+    let span = span!(Level::INFO, "request", path = "/index.html", status = 0);
+    {
+        let _enter = span.enter();
+        event!(Level::INFO, bytes = 1024, "sending response");
+        span.record("status", 200);
+    }
+
+    // TRACE maps to level 6, which this backend has not enabled.
+    event!(Level::TRACE, "not written");
+}
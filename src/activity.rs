@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Chooses the activity ID of each span.
+///
+/// Events written inside a span, and the span's own start and stop events, use
+/// the activity ID chosen when the span was created, and its children use it as
+/// their related activity ID, so any generator keeps them correlated.
+/// Spans that have an OpenTelemetry span context (see the `opentelemetry` feature)
+/// take their activity ID from it instead.
+pub trait ActivityIdGenerator: Send + Sync {
+    /// Returns the activity ID for a new span with the given `tracing` span ID.
+    /// The registry reuses span IDs once spans close.
+    fn activity_id(&self, span_id: u64, metadata: &'static tracing::Metadata<'static>) -> [u8; 16];
+}
+
+static GLOBAL_ACTIVITY_SEED: once_cell::sync::Lazy<[u8; 16]> = once_cell::sync::Lazy::new(|| {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let seed = (now >> 64) as u64 | now as u64;
+    let mut data = [0; 16];
+    let (seed_half, _) = data.split_at_mut(8);
+    seed_half.copy_from_slice(&seed.to_le_bytes());
+    data[0] = 0;
+    data
+});
+
+/// The default generator. A seed taken from the clock when the process first
+/// creates a span, followed by the `tracing` span ID.
+///
+/// This is cheap, but activity IDs repeat when the registry reuses a span ID,
+/// and can collide between processes started at the same time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SeededActivityIds;
+
+impl ActivityIdGenerator for SeededActivityIds {
+    fn activity_id(
+        &self,
+        span_id: u64,
+        _metadata: &'static tracing::Metadata<'static>,
+    ) -> [u8; 16] {
+        let mut activity_id = *GLOBAL_ACTIVITY_SEED;
+        let (_, half) = activity_id.split_at_mut(8);
+        half.copy_from_slice(&span_id.to_le_bytes());
+        activity_id[0] = 1;
+        activity_id
+    }
+}
+
+/// Random version 4 GUIDs, unique across span ID reuse and across processes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomActivityIds;
+
+impl ActivityIdGenerator for RandomActivityIds {
+    fn activity_id(
+        &self,
+        _span_id: u64,
+        _metadata: &'static tracing::Metadata<'static>,
+    ) -> [u8; 16] {
+        let mut activity_id = rand::random::<[u8; 16]>();
+        // Activity IDs are written as little-endian GUIDs, so the version is in the
+        // high nibble of byte 7, and the variant in the high bits of byte 8.
+        activity_id[7] = (activity_id[7] & 0x0f) | 0x40;
+        activity_id[8] = (activity_id[8] & 0x3f) | 0x80;
+        activity_id
+    }
+}
+
+/// Activity IDs 1, 2, 3 and so on in the order spans are created, for tests that
+/// compare activity IDs against known values.
+#[derive(Debug, Default)]
+pub struct SequentialActivityIds {
+    next: AtomicU64,
+}
+
+impl SequentialActivityIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ActivityIdGenerator for SequentialActivityIds {
+    fn activity_id(
+        &self,
+        _span_id: u64,
+        _metadata: &'static tracing::Metadata<'static>,
+    ) -> [u8; 16] {
+        let id = self.next.fetch_add(1, Ordering::Relaxed) + 1;
+        (id as u128).to_be_bytes()
+    }
+}
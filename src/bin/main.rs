@@ -12,9 +12,15 @@ fn main() {
     //     .with_span_events(FmtSpan::FULL)
     //     .without_time();
 
+    #[cfg(not(feature = "global_filter"))]
+    let geneva = LayerBuilder::new_common_schema_events("test2").build_with_target("geneva");
+    // A global filter layer sees everything, so there is no per-target variant
+    #[cfg(feature = "global_filter")]
+    let geneva = LayerBuilder::new_common_schema_events("test2").build();
+
     let subscriber = tracing_subscriber::registry()
         .with(LayerBuilder::new("test").build()) // Collects everything
-        .with(LayerBuilder::new_common_schema_events("test2").build_with_target("geneva"))
+        .with(geneva)
         .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::ACTIVE));
     let _sub = subscriber.try_init();
 
@@ -0,0 +1,369 @@
+//! An in-memory backend that records everything the layer emits.
+//!
+//! Events are decoded into [`CapturedEvent`] values instead of being written
+//! to ETW or user_events, so tests can assert on the output of their
+//! instrumentation on any machine.
+//!
+//! ```
+//! use tracing_etw::{capture::EventCapture, LayerBuilder};
+//! use tracing_subscriber::prelude::*;
+//!
+//! let capture = EventCapture::new();
+//! let subscriber = tracing_subscriber::registry()
+//!     .with(LayerBuilder::new_capture("test_provider", &capture).build());
+//!
+//! tracing::subscriber::with_default(subscriber, || {
+//!     tracing::info!(answer = 42, "hello");
+//! });
+//!
+//! let events = capture.events();
+//! assert_eq!(events.len(), 1);
+//! assert_eq!(
+//!     events[0].field("answer"),
+//!     Some(&tracing_etw::capture::CapturedValue::I64(42))
+//! );
+//! ```
+
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::native::{
+    EventRecord, EventWriter, MetadataFields, SpanLink, SpanRecord, ThreadFields, TraceContext,
+};
+use crate::values::*;
+
+/// The opcode a captured event was written with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CapturedOpcode {
+    /// An event written from `tracing::event!`.
+    Info,
+    /// A span started.
+    Start,
+    /// A span stopped.
+    Stop,
+    /// A started span was entered again.
+    Resume,
+    /// A started span was exited, but has not stopped.
+    Suspend,
+    /// A span was linked to a span it follows from, which is its related activity.
+    Receive,
+    /// An event written with some other opcode through an `etw.opcode` field.
+    Other(u8),
+}
+
+impl From<u8> for CapturedOpcode {
+    fn from(opcode: u8) -> Self {
+        match opcode {
+            0 => CapturedOpcode::Info,
+            1 => CapturedOpcode::Start,
+            2 => CapturedOpcode::Stop,
+            7 => CapturedOpcode::Resume,
+            8 => CapturedOpcode::Suspend,
+            240 => CapturedOpcode::Receive,
+            other => CapturedOpcode::Other(other),
+        }
+    }
+}
+
+/// A decoded field value, keeping the type it was recorded with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CapturedValue {
+    U64(u64),
+    I64(i64),
+    U128(u128),
+    I128(i128),
+    F64(f64),
+    Bool(bool),
+    Str(String),
+    Char(char),
+    Error(ErrorValue),
+}
+
+impl CapturedValue {
+    fn from_value(value: &ValueTypes) -> Self {
+        match value {
+            ValueTypes::U64(u) => CapturedValue::U64(*u),
+            ValueTypes::I64(i) => CapturedValue::I64(*i),
+            ValueTypes::U128(u) => CapturedValue::U128(*u),
+            ValueTypes::I128(i) => CapturedValue::I128(*i),
+            ValueTypes::F64(f) => CapturedValue::F64(*f),
+            ValueTypes::Bool(b) => CapturedValue::Bool(*b),
+            ValueTypes::Str(s) => CapturedValue::Str(s.to_string()),
+            ValueTypes::Char(c) => CapturedValue::Char(*c),
+            ValueTypes::Error(e) => CapturedValue::Error(e.as_ref().clone()),
+        }
+    }
+}
+
+/// A single payload field of a captured event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedField {
+    pub name: &'static str,
+    pub value: CapturedValue,
+}
+
+/// An event as it would have been written by the layer.
+#[derive(Clone, Debug)]
+pub struct CapturedEvent {
+    pub name: String,
+    pub level: u8,
+    pub keyword: u64,
+    pub opcode: CapturedOpcode,
+    pub event_tag: u32,
+    pub timestamp: SystemTime,
+    /// Nanoseconds of `CLOCK_MONOTONIC`, if the layer was built with
+    /// [`TimestampClock::Monotonic`](crate::TimestampClock::Monotonic).
+    pub monotonic_timestamp: Option<u64>,
+    pub activity_id: Option<[u8; 16]>,
+    pub related_activity_id: Option<[u8; 16]>,
+    pub trace_context: Option<TraceContext>,
+    /// Payload fields, in the order they would have been written.
+    pub fields: Vec<CapturedField>,
+}
+
+impl CapturedEvent {
+    /// Get the value of the first field with the given name.
+    pub fn field(&self, name: &str) -> Option<&CapturedValue> {
+        self.fields
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| &f.value)
+    }
+}
+
+impl<T> AddFieldAndValue<T> for &'_ mut Vec<CapturedField> {
+    fn add_field_value(&mut self, fv: &FieldAndValue) {
+        self.push(CapturedField {
+            name: fv.field_name,
+            value: CapturedValue::from_value(fv.value),
+        });
+    }
+}
+
+/// A backend that stores every event in memory.
+///
+/// Clones share the same storage, so a clone can be handed to
+/// [`LayerBuilder::new_capture`](crate::LayerBuilder::new_capture)
+/// and the original kept for inspecting the results.
+#[derive(Clone, Default)]
+pub struct EventCapture {
+    events: Arc<Mutex<Vec<CapturedEvent>>>,
+}
+
+impl EventCapture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A copy of every event captured so far, in the order they were written.
+    pub fn events(&self) -> Vec<CapturedEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Remove and return every event captured so far.
+    pub fn take(&self) -> Vec<CapturedEvent> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+
+    /// Every captured event with the given name.
+    pub fn events_named(&self, name: &str) -> Vec<CapturedEvent> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.name == name)
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.lock().unwrap().is_empty()
+    }
+
+    fn push(&self, event: CapturedEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl CapturedEvent {
+    fn from_span(span: &SpanRecord<'_>, opcode: CapturedOpcode, timestamp: SystemTime) -> Self {
+        // Like the native backends, only span start events carry the callsite metadata.
+        let metadata_fields = if opcode == CapturedOpcode::Start {
+            span.metadata_fields
+        } else {
+            MetadataFields::default()
+        };
+
+        CapturedEvent {
+            name: span.name.to_owned(),
+            level: span.level,
+            keyword: span.keyword,
+            opcode,
+            event_tag: span.event_tag,
+            timestamp,
+            monotonic_timestamp: span.monotonic_timestamp,
+            activity_id: Some(*span.activity_id),
+            related_activity_id: span.related_activity_id.copied(),
+            trace_context: Some(*span.trace_context),
+            fields: captured_fields(
+                span.fields,
+                span.metadata,
+                metadata_fields,
+                span.thread_fields,
+            ),
+        }
+    }
+}
+
+/// The event's fields, preceded by the callsite metadata and thread details the layer
+/// was asked to write, in the same order as the native backends write them.
+fn captured_fields(
+    fields: Fields<'_>,
+    metadata: &tracing::Metadata<'static>,
+    metadata_fields: MetadataFields,
+    thread_fields: ThreadFields,
+) -> Vec<CapturedField> {
+    let mut captured = Vec::new();
+    if let Some(file) = metadata_fields.file(metadata) {
+        captured.push(CapturedField {
+            name: "file",
+            value: CapturedValue::Str(file.to_owned()),
+        });
+    }
+    if let Some(line) = metadata_fields.line(metadata) {
+        captured.push(CapturedField {
+            name: "line",
+            value: CapturedValue::U64(line.into()),
+        });
+    }
+    if let Some(module_path) = metadata_fields.module_path(metadata) {
+        captured.push(CapturedField {
+            name: "module_path",
+            value: CapturedValue::Str(module_path.to_owned()),
+        });
+    }
+    if let Some(target) = metadata_fields.target(metadata) {
+        captured.push(CapturedField {
+            name: "target",
+            value: CapturedValue::Str(target.to_owned()),
+        });
+    }
+    if let Some(thread_id) = thread_fields.thread_id() {
+        captured.push(CapturedField {
+            name: "thread_id",
+            value: CapturedValue::U64(thread_id),
+        });
+    }
+    if let Some(thread_name) = thread_fields.thread_name() {
+        captured.push(CapturedField {
+            name: "thread_name",
+            value: CapturedValue::Str(thread_name.as_ref().to_owned()),
+        });
+    }
+    if let Some(process_id) = thread_fields.process_id() {
+        captured.push(CapturedField {
+            name: "process_id",
+            value: CapturedValue::U64(process_id.into()),
+        });
+    }
+    for f in fields {
+        <&mut Vec<CapturedField> as AddFieldAndValue<Vec<CapturedField>>>::add_field_value(
+            &mut &mut captured,
+            &f,
+        );
+    }
+    captured
+}
+
+impl EventWriter for EventCapture {
+    #[inline(always)]
+    fn enabled(&self, _level: u8, _keyword: u64) -> bool {
+        true
+    }
+
+    #[inline(always)]
+    fn supports_enable_callback() -> bool {
+        false
+    }
+
+    fn span_start(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        self.push(CapturedEvent::from_span(
+            span,
+            CapturedOpcode::Start,
+            timestamp,
+        ));
+    }
+
+    fn span_stop(
+        self: Pin<&Self>,
+        span: &SpanRecord<'_>,
+        start_stop_times: (SystemTime, SystemTime),
+    ) {
+        self.push(CapturedEvent::from_span(
+            span,
+            CapturedOpcode::Stop,
+            start_stop_times.1,
+        ));
+    }
+
+    fn span_resume(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        self.push(CapturedEvent::from_span(
+            span,
+            CapturedOpcode::Resume,
+            timestamp,
+        ));
+    }
+
+    fn span_suspend(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        self.push(CapturedEvent::from_span(
+            span,
+            CapturedOpcode::Suspend,
+            timestamp,
+        ));
+    }
+
+    fn span_link(self: Pin<&Self>, span: &SpanRecord<'_>, link: &SpanLink, timestamp: SystemTime) {
+        let span = SpanRecord {
+            related_activity_id: Some(&link.activity_id),
+            fields: Fields::new(&[]),
+            ..*span
+        };
+        self.push(CapturedEvent::from_span(
+            &span,
+            CapturedOpcode::Receive,
+            timestamp,
+        ));
+    }
+
+    fn write_record(self: Pin<&Self>, event: &EventRecord<'_>) {
+        self.push(CapturedEvent {
+            name: event.name.to_owned(),
+            level: event.level,
+            keyword: event.keyword,
+            opcode: event.opcode.into(),
+            event_tag: event.event_tag,
+            timestamp: event.timestamp,
+            monotonic_timestamp: event.monotonic_timestamp,
+            activity_id: event.activity_id.copied(),
+            related_activity_id: event.related_activity_id.copied(),
+            trace_context: event.trace_context.copied(),
+            fields: captured_fields(
+                event.fields,
+                event.metadata,
+                event.metadata_fields,
+                event.thread_fields,
+            ),
+        });
+    }
+}
@@ -0,0 +1,462 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use tracing::Subscriber;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+use crate::native::{self, EventMode};
+use crate::{BuildError, ConfigError, EtwLayerBuilder, LayerBuilder};
+
+/// The configuration of a provider, read at runtime rather than compiled in.
+///
+/// It can be parsed from a compact spec string, which is the provider name
+/// optionally followed by `;`-separated options:
+///
+/// ```text
+/// myapp;id=6f9619ff-8b86-d011-b42d-00c04fc964ff;group=mygroup;keyword=0x10;common_schema=true;targets=myapp=info,myapp::db=trace
+/// ```
+///
+/// - `id`: the provider ID, as a GUID. Defaults to the ID generated from the name.
+/// - `group`: the provider group, a GUID on Windows and a group name on Linux.
+///   Ignored on other platforms.
+/// - `keyword`: the default keyword, in decimal or `0x` hexadecimal.
+/// - `common_schema`: `true` to write Common Schema events. Defaults to `false`.
+/// - `targets`: target directives, in the format of
+///   [`Targets`](tracing_subscriber::filter::Targets). This must be the last option.
+///
+/// With the `serde` feature, it can also be serialized and deserialized, using the
+/// option names above as keys and `keywords` for [`ProviderConfig::target_keywords`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+#[non_exhaustive]
+pub struct ProviderConfig {
+    pub name: String,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            rename = "id",
+            default,
+            deserialize_with = "deserialize_guid",
+            serialize_with = "serialize_guid",
+            skip_serializing_if = "Option::is_none"
+        )
+    )]
+    pub provider_id: Option<tracelogging::Guid>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub group: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "keyword", default, skip_serializing_if = "Option::is_none")
+    )]
+    pub default_keyword: Option<u64>,
+    /// Keywords for events whose target starts with the key.
+    /// See [`EtwLayerBuilder::with_target_keyword`].
+    #[cfg_attr(feature = "serde", serde(rename = "keywords", default))]
+    pub target_keywords: BTreeMap<String, u64>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub common_schema: bool,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub targets: Option<String>,
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_guid<'de, D>(deserializer: D) -> Result<Option<tracelogging::Guid>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+
+    let value = String::deserialize(deserializer)?;
+    tracelogging::Guid::try_parse(&value)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(invalid_value("id", &value)))
+}
+
+#[cfg(feature = "serde")]
+fn serialize_guid<S>(guid: &Option<tracelogging::Guid>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match guid {
+        // Written in the same form try_parse reads
+        Some(guid) => {
+            let bytes = guid.to_utf8_bytes();
+            serializer.serialize_str(std::str::from_utf8(&bytes).unwrap())
+        }
+        None => serializer.serialize_none(),
+    }
+}
+
+fn invalid_value(option: &str, value: &str) -> ConfigError {
+    ConfigError::InvalidValue {
+        option: option.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn parse_keyword(value: &str) -> Option<u64> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_targets(value: &str) -> Result<Targets, ConfigError> {
+    value
+        .parse::<Targets>()
+        .map_err(|_| invalid_value("targets", value))
+}
+
+impl ProviderConfig {
+    pub fn new(name: &str) -> Self {
+        ProviderConfig {
+            name: name.to_owned(),
+            provider_id: None,
+            group: None,
+            default_keyword: None,
+            target_keywords: BTreeMap::new(),
+            common_schema: false,
+            targets: None,
+        }
+    }
+
+    /// Create a builder for this provider, ready to be customized further or built.
+    ///
+    /// Returns [`ConfigError::Build`] if the provider's name, ID, group or keywords
+    /// would be rejected by [`EtwLayerBuilder::try_build`].
+    pub fn into_builder(self) -> Result<ConfiguredLayerBuilder, ConfigError> {
+        let targets = self.targets.as_deref().map(parse_targets).transpose()?;
+
+        if self.common_schema {
+            #[cfg(feature = "common_schema")]
+            return Ok(ConfiguredLayerBuilder::CommonSchema {
+                builder: self.configure(LayerBuilder::new_common_schema_events(&self.name))?,
+                targets,
+            });
+
+            #[cfg(not(feature = "common_schema"))]
+            return Err(invalid_value("common_schema", "true"));
+        }
+
+        Ok(ConfiguredLayerBuilder::Native {
+            builder: self.configure(LayerBuilder::new(&self.name))?,
+            targets,
+        })
+    }
+
+    fn configure<Mode>(
+        &self,
+        mut builder: EtwLayerBuilder<Mode>,
+    ) -> Result<EtwLayerBuilder<Mode>, ConfigError>
+    where
+        Mode: EventMode,
+    {
+        if let Some(provider_id) = self.provider_id {
+            builder = builder.with_provider_id(provider_id);
+        }
+
+        if let Some(keyword) = self.default_keyword {
+            builder = builder.with_default_keyword(keyword);
+        }
+
+        for (target, keyword) in &self.target_keywords {
+            builder = builder.with_target_keyword(target, *keyword);
+        }
+
+        if let Some(ref group) = self.group {
+            #[cfg(target_os = "windows")]
+            {
+                let guid = tracelogging::Guid::try_parse(group)
+                    .ok_or_else(|| invalid_value("group", group))?;
+                builder = builder.with_provider_group(guid);
+            }
+
+            #[cfg(target_os = "linux")]
+            {
+                builder = builder.with_provider_group(group);
+            }
+
+            #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+            let _ = group;
+        }
+
+        builder
+            .validate_config(true)
+            .map_err(|error| ConfigError::Build {
+                provider: self.name.clone(),
+                error,
+            })?;
+
+        Ok(builder)
+    }
+}
+
+impl FromStr for ProviderConfig {
+    type Err = ConfigError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut parts = spec.trim().splitn(2, ';');
+
+        let name = parts.next().unwrap_or_default().trim();
+        if name.is_empty() {
+            return Err(ConfigError::MissingProviderName);
+        }
+
+        let mut config = ProviderConfig::new(name);
+
+        let mut rest = parts.next().unwrap_or_default();
+        while !rest.trim().is_empty() {
+            let (option, value) = rest.split_once('=').unwrap_or((rest, ""));
+            let option = option.trim();
+
+            // Target directives contain ',' and '=', so they run to the end of the spec
+            if option == "targets" {
+                let value = value.trim();
+                parse_targets(value)?;
+                config.targets = Some(value.to_owned());
+                break;
+            }
+
+            let (value, next) = value.split_once(';').unwrap_or((value, ""));
+            let value = value.trim();
+            rest = next;
+
+            match option {
+                "id" => {
+                    config.provider_id = Some(
+                        tracelogging::Guid::try_parse(value)
+                            .ok_or_else(|| invalid_value(option, value))?,
+                    );
+                }
+                "group" => config.group = Some(value.to_owned()),
+                "keyword" => {
+                    config.default_keyword =
+                        Some(parse_keyword(value).ok_or_else(|| invalid_value(option, value))?);
+                }
+                "common_schema" => {
+                    config.common_schema = value
+                        .parse::<bool>()
+                        .map_err(|_| invalid_value(option, value))?;
+                }
+                _ => return Err(ConfigError::UnknownOption(option.to_owned())),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// The configuration of several providers, each written to by its own layer.
+///
+/// With the `serde` feature, it can be serialized to and deserialized from any
+/// format serde supports, such as this TOML:
+///
+/// ```toml
+/// [[providers]]
+/// name = "myapp"
+/// keyword = 0x10
+/// targets = "myapp=info"
+///
+/// [[providers]]
+/// name = "myapp_requests"
+/// common_schema = true
+/// keywords = { "myapp::http" = 0x20 }
+/// targets = "myapp::http=trace"
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+#[non_exhaustive]
+pub struct EtwConfig {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub providers: Vec<ProviderConfig>,
+}
+
+impl EtwConfig {
+    pub fn new(providers: Vec<ProviderConfig>) -> Self {
+        EtwConfig { providers }
+    }
+
+    /// Create a builder for each provider, in order.
+    pub fn into_builders(self) -> Result<Vec<ConfiguredLayerBuilder>, ConfigError> {
+        self.providers
+            .into_iter()
+            .map(ProviderConfig::into_builder)
+            .collect()
+    }
+
+    /// Build a single layer that writes to every configured provider,
+    /// each filtered to its own targets.
+    ///
+    /// Returns an error if any provider's configuration cannot be parsed, or is
+    /// rejected by [`ProviderConfig::into_builder`]. Registration failures are
+    /// ignored, and that provider will not write any events.
+    /// Use [`EtwConfig::try_build`] to handle those as well.
+    pub fn build<S>(self) -> Result<Box<dyn Layer<S> + Send + Sync>, ConfigError>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    {
+        // into_builders has already validated every builder, so building them cannot panic
+        let layers = self
+            .into_builders()?
+            .into_iter()
+            .map(ConfiguredLayerBuilder::build)
+            .collect::<Vec<_>>();
+
+        Ok(Box::new(layers))
+    }
+
+    /// Like [`EtwConfig::build`], but returns an error naming the provider if any
+    /// provider's builder configuration is invalid or it could not be registered.
+    pub fn try_build<S>(self) -> Result<Box<dyn Layer<S> + Send + Sync>, ConfigError>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    {
+        let mut layers = Vec::with_capacity(self.providers.len());
+        for provider in self.providers {
+            let name = provider.name.clone();
+            let layer =
+                provider
+                    .into_builder()?
+                    .try_build()
+                    .map_err(|error| ConfigError::Build {
+                        provider: name,
+                        error,
+                    })?;
+            layers.push(layer);
+        }
+
+        Ok(Box::new(layers))
+    }
+}
+
+/// An [`EtwLayerBuilder`] for a provider whose event format is only known at runtime.
+///
+/// Match on it to customize the builder further, or build it as a boxed layer.
+#[non_exhaustive]
+pub enum ConfiguredLayerBuilder {
+    Native {
+        builder: EtwLayerBuilder<native::Provider>,
+        targets: Option<Targets>,
+    },
+    #[cfg(feature = "common_schema")]
+    CommonSchema {
+        builder: EtwLayerBuilder<native::common_schema::Provider>,
+        targets: Option<Targets>,
+    },
+}
+
+impl ConfiguredLayerBuilder {
+    /// Build the layer, filtered to the configured targets if there are any.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the builder's configuration is invalid. Registration failures
+    /// are ignored, and the layer will not write any events.
+    /// Use [`ConfiguredLayerBuilder::try_build`] to handle either.
+    pub fn build<S>(self) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    {
+        match self {
+            ConfiguredLayerBuilder::Native { builder, targets } => build_boxed(builder, targets),
+            #[cfg(feature = "common_schema")]
+            ConfiguredLayerBuilder::CommonSchema { builder, targets } => {
+                build_boxed(builder, targets)
+            }
+        }
+    }
+
+    /// Like [`ConfiguredLayerBuilder::build`], but returns an error
+    /// for an invalid configuration or if the provider could not be registered.
+    pub fn try_build<S>(self) -> Result<Box<dyn Layer<S> + Send + Sync>, BuildError>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    {
+        match self {
+            ConfiguredLayerBuilder::Native { builder, targets } => {
+                try_build_boxed(builder, targets)
+            }
+            #[cfg(feature = "common_schema")]
+            ConfiguredLayerBuilder::CommonSchema { builder, targets } => {
+                try_build_boxed(builder, targets)
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "global_filter"))]
+fn build_boxed<Mode, S>(
+    builder: EtwLayerBuilder<Mode>,
+    targets: Option<Targets>,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    Mode: EventMode,
+    Mode::Provider: Send + Sync,
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    match targets {
+        Some(targets) => Box::new(builder.build_with_targets(targets)),
+        None => Box::new(builder.build()),
+    }
+}
+
+#[cfg(not(feature = "global_filter"))]
+fn try_build_boxed<Mode, S>(
+    builder: EtwLayerBuilder<Mode>,
+    targets: Option<Targets>,
+) -> Result<Box<dyn Layer<S> + Send + Sync>, BuildError>
+where
+    Mode: EventMode,
+    Mode::Provider: Send + Sync,
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    Ok(match targets {
+        Some(targets) => Box::new(builder.try_build_with_targets(targets)?),
+        None => Box::new(builder.try_build()?),
+    })
+}
+
+// With a global filter, the targets can only be applied as a per-layer filter.
+#[cfg(feature = "global_filter")]
+fn build_boxed<Mode, S>(
+    builder: EtwLayerBuilder<Mode>,
+    targets: Option<Targets>,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    Mode: EventMode,
+    Mode::Provider: Send + Sync,
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    match targets {
+        Some(targets) => Box::new(builder.build().with_filter(targets)),
+        None => Box::new(builder.build()),
+    }
+}
+
+#[cfg(feature = "global_filter")]
+fn try_build_boxed<Mode, S>(
+    builder: EtwLayerBuilder<Mode>,
+    targets: Option<Targets>,
+) -> Result<Box<dyn Layer<S> + Send + Sync>, BuildError>
+where
+    Mode: EventMode,
+    Mode::Provider: Send + Sync,
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    Ok(match targets {
+        Some(targets) => Box::new(builder.try_build()?.with_filter(targets)),
+        None => Box::new(builder.try_build()?),
+    })
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+
+/// The reasons [`EtwLayerBuilder::try_build`](crate::EtwLayerBuilder::try_build) can fail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// The provider name cannot be used on this platform.
+    InvalidProviderName(String),
+    /// EventHeader provider group names may only contain lower case ASCII letters and digits.
+    InvalidGroupName(String),
+    /// The provider ID is all zeroes.
+    ZeroProviderId,
+    /// The provider group ID is all zeroes.
+    ZeroGroupId,
+    /// Keyword 0 cannot be filtered on by ETW or user_events sessions.
+    ZeroKeyword,
+    /// The OS rejected the provider or one of its tracepoints.
+    /// The code is a Win32 error code on Windows and an errno value on Linux.
+    RegistrationFailed(i32),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::InvalidProviderName(name) => {
+                write!(f, "invalid provider name {:?}", name)
+            }
+            BuildError::InvalidGroupName(name) => {
+                write!(
+                    f,
+                    "invalid provider group name {:?}, must be lower case ASCII or numeric digits",
+                    name
+                )
+            }
+            BuildError::ZeroProviderId => write!(f, "provider ID must not be zeroes"),
+            BuildError::ZeroGroupId => write!(f, "provider group ID must not be zeroes"),
+            BuildError::ZeroKeyword => write!(f, "keyword must not be 0"),
+            BuildError::RegistrationFailed(code) => {
+                write!(f, "provider registration failed with error {}", code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// The reasons a provider configuration, such as the spec read by
+/// [`LayerBuilder::from_env`](crate::LayerBuilder::from_env), can be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The environment variable is not set, or is not valid Unicode.
+    MissingVariable(String),
+    /// The spec does not start with a provider name.
+    MissingProviderName,
+    /// The spec contains an option that is not recognized.
+    UnknownOption(String),
+    /// An option has a value that cannot be parsed or used.
+    InvalidValue { option: String, value: String },
+    /// A provider's configuration is invalid, or the provider could not be built.
+    Build { provider: String, error: BuildError },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingVariable(name) => {
+                write!(f, "environment variable {:?} is not set", name)
+            }
+            ConfigError::MissingProviderName => write!(f, "provider name is missing"),
+            ConfigError::UnknownOption(option) => write!(f, "unknown option {:?}", option),
+            ConfigError::InvalidValue { option, value } => {
+                write!(f, "invalid value {:?} for option {:?}", value, option)
+            }
+            ConfigError::Build { provider, error } => {
+                write!(f, "provider {:?}: {}", provider, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Build { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
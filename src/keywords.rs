@@ -0,0 +1,66 @@
+/// The keyword to write each span and event with, chosen from its target.
+#[derive(Clone, Debug)]
+pub(crate) struct KeywordMap {
+    default_keyword: u64,
+    // Sorted by descending prefix length, so the first match is the longest one
+    targets: Vec<(String, u64)>,
+    // Keywords no target maps to, which callsites can still choose with an `etw.keyword` field
+    overrides: Vec<u64>,
+}
+
+impl KeywordMap {
+    pub(crate) fn new(default_keyword: u64, targets: &[(String, u64)], overrides: &[u64]) -> Self {
+        let mut targets = targets.to_vec();
+        targets.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        KeywordMap {
+            default_keyword,
+            targets,
+            overrides: overrides.to_vec(),
+        }
+    }
+
+    /// The keyword for the given target. Like `tracing_subscriber::filter::Targets`,
+    /// a prefix matches any target that starts with it.
+    pub(crate) fn keyword(&self, target: &str) -> u64 {
+        self.targets
+            .iter()
+            .find(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .map_or(self.default_keyword, |(_, keyword)| *keyword)
+    }
+
+    /// Whether `keyword` is the default keyword, a target keyword or an override keyword.
+    pub(crate) fn contains(&self, keyword: u64) -> bool {
+        self.default_keyword == keyword
+            || self.targets.iter().any(|(_, kw)| *kw == keyword)
+            || self.overrides.contains(&keyword)
+    }
+
+    pub(crate) fn default_keyword(&self) -> u64 {
+        self.default_keyword
+    }
+
+    pub(crate) fn targets(&self) -> &[(String, u64)] {
+        &self.targets
+    }
+
+    pub(crate) fn overrides(&self) -> &[u64] {
+        &self.overrides
+    }
+
+    /// Every keyword that can be chosen, starting with the default keyword.
+    pub(crate) fn keywords(&self) -> Vec<u64> {
+        let mut keywords = vec![self.default_keyword];
+        for keyword in self
+            .targets
+            .iter()
+            .map(|(_, keyword)| keyword)
+            .chain(&self.overrides)
+        {
+            if !keywords.contains(keyword) {
+                keywords.push(*keyword);
+            }
+        }
+        keywords
+    }
+}
@@ -1,61 +1,438 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 use std::{pin::Pin, sync::Arc};
 
+use arc_swap::ArcSwap;
 use tracelogging::Guid;
+#[cfg(not(feature = "global_filter"))]
 use tracing::metadata::LevelFilter;
 use tracing::{span, Subscriber};
+#[cfg(not(feature = "global_filter"))]
 use tracing_subscriber::filter::{combinator::And, FilterExt, Filtered, Targets};
 use tracing_subscriber::layer::Filter;
 use tracing_subscriber::{registry::LookupSpan, Layer};
 
 use crate::native::ProviderGroup;
 
-use crate::native::{EventMode, EventWriter};
+use crate::activity::{ActivityIdGenerator, SeededActivityIds};
+use crate::capture::EventCapture;
+use crate::keywords::KeywordMap;
+use crate::native::{
+    CustomBackend, EventMode, EventRecord, EventWriter, MetadataFields, SpanLink, SpanRecord,
+    ThreadFields, TimestampClock, TraceContext,
+};
+use crate::propagation::{RemoteParent, WithContext};
+use crate::reload::FilterSettings;
+#[cfg(not(feature = "global_filter"))]
+use crate::reload::ReloadHandle;
 use crate::values::*;
-use crate::{map_level, native};
-
-pub(crate) static GLOBAL_ACTIVITY_SEED: once_cell::sync::Lazy<[u8; 16]> =
-    once_cell::sync::Lazy::new(|| {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        let seed = (now >> 64) as u64 | now as u64;
-        let mut data = [0; 16];
-        let (seed_half, _) = data.split_at_mut(8);
-        seed_half.copy_from_slice(&seed.to_le_bytes());
-        data[0] = 0;
-        data
-    });
+use crate::{
+    map_level, native, otel, BuildError, ConfigError, ConfiguredLayerBuilder, ProviderConfig,
+};
 
+/// Gives each layer an ID, so layers stacked in one subscriber can keep their own span state.
+static NEXT_LAYER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A span's data, shared by every layer in the subscriber that writes the span.
+/// The layer that creates it records the span's fields and links.
 struct EtwLayerData {
+    owner: usize,
     fields: Box<[FieldValueIndex]>,
-    activity_id: [u8; 16], // // if set, byte 0 is 1 and 64-bit span ID in the lower 8 bytes
-    related_activity_id: [u8; 16], // if set, byte 0 is 1 and 64-bit span ID in the lower 8 bytes
+    // The names the fields are copied onto events with, looked up the first time they are needed
+    prefixed_names: once_cell::sync::OnceCell<&'static [&'static str]>,
+    activity_id: [u8; 16], // from the layer's ActivityIdGenerator, or derived from the OTel span context
+    related_activity_id: Option<[u8; 16]>,
+    trace_context: TraceContext,
+    overrides: HeaderOverrides,
+    layers: Vec<(usize, SpanState)>,
+}
+
+/// What one layer has written for a span.
+#[derive(Clone, Copy)]
+struct SpanState {
     start_time: SystemTime,
+    // Only tracked when the span mode writes a single stop event per span
+    started: bool,
+    busy: Duration,
+    idle: Duration,
+    last_transition: Instant,
+    // False if the span's `etw.level` or `etw.keyword` override was not enabled when it was created
+    enabled: bool,
+}
+
+impl SpanState {
+    fn new() -> Self {
+        SpanState {
+            start_time: SystemTime::UNIX_EPOCH,
+            started: false,
+            busy: Duration::ZERO,
+            idle: Duration::ZERO,
+            last_transition: Instant::now(),
+            enabled: true,
+        }
+    }
+}
+
+impl EtwLayerData {
+    fn state(&self, layer: usize) -> SpanState {
+        self.layers
+            .iter()
+            .find(|(id, _)| *id == layer)
+            .map_or_else(SpanState::new, |(_, state)| *state)
+    }
+
+    fn set_state(&mut self, layer: usize, state: SpanState) {
+        match self.layers.iter_mut().find(|(id, _)| *id == layer) {
+            Some((_, existing)) => *existing = state,
+            None => self.layers.push((layer, state)),
+        }
+    }
+}
+
+/// Which span fields are copied onto each event, so an event is self-contained.
+///
+/// Span fields are written as `span_name.field_name`, so they are never mistaken for
+/// the event's own fields. If a span closer to the event already wrote that name,
+/// the field is skipped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanFieldPropagation {
+    /// Only write the event's own fields.
+    #[default]
+    None,
+    /// Also write the fields of the span the event occurred in.
+    CurrentSpan,
+    /// Also write the fields of the span the event occurred in and all of its ancestors.
+    FullScope,
+}
+
+/// How spans are written as start and stop events.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanMode {
+    /// Write a start event each time a span is entered, and a stop event each time it is exited.
+    #[default]
+    PerEnter,
+    /// Write one start event when a span is created, and one stop event when it is closed.
+    /// The stop event has two extra fields, `busy_ns` and `idle_ns`, with the total time
+    /// the span was entered and not entered.
+    Lifecycle,
+    /// For spans that are entered many times, such as instrumented futures.
+    /// Write a start event the first time a span is entered, a suspend event each time
+    /// it is exited, a resume event each time it is entered again, and a stop event
+    /// when it is closed. The stop event has the same extra fields as [`SpanMode::Lifecycle`].
+    Async,
+}
+
+/// How events written with `tracing::event!` and its shorthands are named.
+///
+/// Callsite names such as `event src/main.rs:25` change whenever code moves,
+/// so they make poor ETW or tracepoint event names. Span names are not affected.
+#[derive(Clone, Default)]
+pub enum EventNaming {
+    /// The callsite name, such as `event src/main.rs:25`.
+    #[default]
+    CallsiteName,
+    /// The event's target, such as `myapp::db`.
+    Target,
+    /// The event's target followed by its message, such as `myapp::db: connected`.
+    /// Messages that interpolate values produce a different name for each value.
+    TargetAndMessage,
+    /// The string value of the given field, or the callsite name if the event
+    /// does not have one. The field is still written as part of the payload.
+    Field(&'static str),
+    /// A name chosen from the event's callsite metadata.
+    Custom(Arc<dyn Fn(&'static tracing::Metadata<'static>) -> String + Send + Sync>),
+}
+
+impl std::fmt::Debug for EventNaming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventNaming::CallsiteName => f.write_str("CallsiteName"),
+            EventNaming::Target => f.write_str("Target"),
+            EventNaming::TargetAndMessage => f.write_str("TargetAndMessage"),
+            EventNaming::Field(name) => f.debug_tuple("Field").field(name).finish(),
+            EventNaming::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+impl EventNaming {
+    fn event_name<'a>(
+        &self,
+        metadata: &'static tracing::Metadata<'static>,
+        fields: &'a [FieldValueIndex],
+    ) -> Cow<'a, str> {
+        let field_str = |name: &str| {
+            fields.iter().find_map(|f| match f.value {
+                Some(ValueTypes::Str(ref s)) if f.field == name => Some(s.as_ref()),
+                _ => None,
+            })
+        };
+
+        match self {
+            EventNaming::CallsiteName => Cow::Borrowed(metadata.name()),
+            EventNaming::Target => Cow::Borrowed(metadata.target()),
+            EventNaming::TargetAndMessage => match field_str("message") {
+                Some(message) => Cow::Owned(format!("{}: {}", metadata.target(), message)),
+                None => Cow::Borrowed(metadata.target()),
+            },
+            EventNaming::Field(name) => {
+                Cow::Borrowed(field_str(name).unwrap_or_else(|| metadata.name()))
+            }
+            EventNaming::Custom(f) => Cow::Owned(f(metadata)),
+        }
+    }
+}
+
+/// Which spans and events [`EtwLayerBuilder::with_route`] sends to another provider.
+///
+/// Routes only look at callsite metadata, so a span's start, stop and other events
+/// all go to the same provider.
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum ProviderRoute {
+    /// Spans and events whose target starts with the given prefix.
+    Target(String),
+    /// Spans and events that have a field with the given name, whether or not it has a value.
+    Field(&'static str),
+    /// Spans and events for which the closure returns true.
+    Custom(Arc<dyn Fn(&tracing::Metadata<'_>) -> bool + Send + Sync>),
+}
+
+impl std::fmt::Debug for ProviderRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProviderRoute::Target(prefix) => f.debug_tuple("Target").field(prefix).finish(),
+            ProviderRoute::Field(name) => f.debug_tuple("Field").field(name).finish(),
+            ProviderRoute::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+impl ProviderRoute {
+    fn matches(&self, metadata: &tracing::Metadata<'_>) -> bool {
+        match self {
+            ProviderRoute::Target(prefix) => metadata.target().starts_with(prefix.as_str()),
+            ProviderRoute::Field(name) => metadata.fields().field(name).is_some(),
+            ProviderRoute::Custom(f) => f(metadata),
+        }
+    }
+}
+
+/// The layer's own provider, and the providers it routes some spans and events to.
+struct Providers<P> {
+    default: Pin<Arc<P>>,
+    routes: Box<[(ProviderRoute, Pin<Arc<P>>)]>,
+    // The index of the route each callsite matched, or None for the default provider.
+    // Routes only look at callsite metadata, so they are matched once per callsite.
+    resolved: ArcSwap<HashMap<tracing::callsite::Identifier, Option<usize>>>,
+}
+
+impl<P> Providers<P> {
+    fn new(default: Pin<Arc<P>>, routes: Box<[(ProviderRoute, Pin<Arc<P>>)]>) -> Self {
+        Providers {
+            default,
+            routes,
+            resolved: ArcSwap::default(),
+        }
+    }
+
+    /// The provider for spans and events from this callsite. The first matching route wins.
+    fn route(&self, metadata: &tracing::Metadata<'_>) -> Pin<&P> {
+        if self.routes.is_empty() {
+            return self.default.as_ref();
+        }
+
+        let callsite = metadata.callsite();
+        let resolved = self.resolved.load().get(&callsite).copied();
+        let index = resolved.unwrap_or_else(|| {
+            let index = self
+                .routes
+                .iter()
+                .position(|(route, _)| route.matches(metadata));
+            self.resolved.rcu(|resolved| {
+                let mut resolved = HashMap::clone(resolved);
+                resolved.insert(callsite.clone(), index);
+                resolved
+            });
+            index
+        });
+
+        index
+            .map_or(&self.default, |index| &self.routes[index].1)
+            .as_ref()
+    }
+}
+
+/// A provider that [`EtwLayerBuilder::with_route`] writes some spans and events to.
+///
+/// It only has a name, ID and group (or backend). Its keywords, filters and
+/// every other setting are those of the layer that routes to it.
+pub struct RoutedProvider<Mode>
+where
+    Mode: EventMode,
+{
+    name: String,
+    id: tracelogging::Guid,
+    group: ProviderGroup,
+    backend: Mode::Backend,
+}
+
+impl<Mode> RoutedProvider<Mode>
+where
+    Mode: EventMode<Backend = ()>,
+{
+    /// A provider that writes the same kind of events as the layer that routes to it.
+    pub fn new(name: &str) -> Self {
+        RoutedProvider {
+            name: name.to_owned(),
+            id: Guid::from_name(name),
+            group: ProviderGroup::Unset,
+            backend: (),
+        }
+    }
+}
+
+impl<B> RoutedProvider<CustomBackend<B>>
+where
+    B: EventWriter + 'static,
+{
+    /// A provider for a layer built with [`LayerBuilder::with_backend`],
+    /// which writes to its own `backend`.
+    pub fn with_backend(name: &str, backend: B) -> Self {
+        RoutedProvider {
+            name: name.to_owned(),
+            id: Guid::from_name(name),
+            group: ProviderGroup::Unset,
+            backend: Arc::pin(backend),
+        }
+    }
+}
+
+impl RoutedProvider<CustomBackend<EventCapture>> {
+    /// A provider for a layer built with [`LayerBuilder::new_capture`],
+    /// which records its events into `capture`.
+    pub fn new_capture(name: &str, capture: &EventCapture) -> Self {
+        Self::with_backend(name, capture.clone())
+    }
+}
+
+impl<Mode> RoutedProvider<Mode>
+where
+    Mode: EventMode,
+{
+    /// Assign a provider ID rather than use one generated from the provider name.
+    /// See [`EtwLayerBuilder::with_provider_id`].
+    pub fn with_provider_id(mut self, guid: tracelogging::Guid) -> Self {
+        self.id = guid;
+        self
+    }
+
+    /// Set the ETW provider group to join this provider to.
+    /// See [`EtwLayerBuilder::with_provider_group`].
+    #[cfg(any(target_os = "windows", doc))]
+    pub fn with_provider_group(mut self, group_id: tracelogging::Guid) -> Self {
+        self.group = ProviderGroup::Windows(group_id);
+        self
+    }
+
+    /// Set the EventHeader provider group to join this provider to.
+    /// See [`EtwLayerBuilder::with_provider_group`].
+    #[cfg(any(target_os = "linux", doc))]
+    pub fn with_provider_group(mut self, name: &str) -> Self {
+        self.group = ProviderGroup::Linux(Cow::Owned(name.to_owned()));
+        self
+    }
+}
+
+/// Generate a random W3C trace ID. An all-zero trace ID is invalid.
+fn new_trace_id() -> [u8; 16] {
+    loop {
+        let id = rand::random::<u128>();
+        if id != 0 {
+            return id.to_be_bytes();
+        }
+    }
+}
+
+/// Generate a random W3C span ID. An all-zero span ID is invalid.
+/// The registry's span IDs are reused and repeat across processes, so they can't be used.
+fn new_span_id() -> [u8; 8] {
+    loop {
+        let id = rand::random::<u64>();
+        if id != 0 {
+            return id.to_be_bytes();
+        }
+    }
 }
 
 #[doc(hidden)]
-pub struct EtwLayerBuilder<Mode> {
+pub struct EtwLayerBuilder<Mode>
+where
+    Mode: EventMode,
+{
     pub(crate) provider_name: String,
     pub(crate) provider_id: tracelogging::Guid,
     pub(crate) provider_group: native::ProviderGroup,
     pub(crate) default_keyword: u64,
+    pub(crate) target_keywords: Vec<(String, u64)>,
+    pub(crate) override_keywords: Vec<u64>,
+    pub(crate) timestamp_clock: TimestampClock,
+    pub(crate) span_mode: SpanMode,
+    pub(crate) event_naming: EventNaming,
+    pub(crate) metadata_fields: MetadataFields,
+    pub(crate) thread_fields: ThreadFields,
+    pub(crate) span_fields: SpanFieldPropagation,
+    pub(crate) span_update_events: bool,
+    pub(crate) activity_ids: Arc<dyn ActivityIdGenerator>,
+    pub(crate) backend: Mode::Backend,
+    pub(crate) routes: Vec<(ProviderRoute, RoutedProvider<Mode>)>,
     _m: PhantomData<Mode>,
 }
 
 pub struct LayerBuilder {}
 
 impl LayerBuilder {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(name: &str) -> EtwLayerBuilder<native::Provider> {
-        EtwLayerBuilder::<native::Provider> {
-            provider_name: name.to_owned(),
-            provider_id: Guid::from_name(name),
-            provider_group: native::ProviderGroup::Unset,
-            default_keyword: 1,
-            _m: PhantomData,
-        }
+        EtwLayerBuilder::with_parts(name, ())
+    }
+
+    /// Configure a provider from the spec in the environment variable `var`,
+    /// for example `TRACING_ETW=myapp;keyword=0x10;targets=myapp=info`.
+    /// See [`ProviderConfig`] for the format of the spec.
+    ///
+    /// Whether the provider writes Common Schema events is part of the spec, so
+    /// this returns a [`ConfiguredLayerBuilder`] that can hold either kind of builder.
+    /// A spec that parses but describes an invalid provider, such as one with a zero
+    /// keyword, is rejected with [`ConfigError::Build`].
+    pub fn from_env(var: &str) -> Result<ConfiguredLayerBuilder, ConfigError> {
+        let spec = std::env::var(var).map_err(|_| ConfigError::MissingVariable(var.to_owned()))?;
+        spec.parse::<ProviderConfig>()?.into_builder()
+    }
+
+    /// Record events into the given [`EventCapture`] instead of writing them
+    /// to ETW or user_events.
+    /// Intended for testing instrumentation; the captured events can be
+    /// inspected through `capture` (or any clone of it) after they are written.
+    pub fn new_capture(
+        name: &str,
+        capture: &EventCapture,
+    ) -> EtwLayerBuilder<CustomBackend<EventCapture>> {
+        Self::with_backend(name, capture.clone())
+    }
+
+    /// For advanced scenarios.
+    /// Write spans and events to a caller-supplied [`EventWriter`] instead of
+    /// ETW or user_events. The layer still takes care of span bookkeeping,
+    /// activity IDs and filtering.
+    pub fn with_backend<B>(name: &str, backend: B) -> EtwLayerBuilder<CustomBackend<B>>
+    where
+        B: EventWriter + 'static,
+    {
+        EtwLayerBuilder::with_parts(name, Arc::pin(backend))
     }
 
     /// For advanced scenarios.
@@ -68,20 +445,37 @@ impl LayerBuilder {
     pub fn new_common_schema_events(
         name: &str,
     ) -> EtwLayerBuilder<native::common_schema::Provider> {
-        EtwLayerBuilder::<native::common_schema::Provider> {
+        EtwLayerBuilder::with_parts(name, ())
+    }
+}
+
+impl<Mode> EtwLayerBuilder<Mode>
+where
+    Mode: EventMode,
+{
+    /// A builder with every setting at its default, shared by the [`LayerBuilder`] constructors.
+    fn with_parts(name: &str, backend: Mode::Backend) -> Self {
+        EtwLayerBuilder {
             provider_name: name.to_owned(),
             provider_id: Guid::from_name(name),
             provider_group: native::ProviderGroup::Unset,
             default_keyword: 1,
+            target_keywords: Vec::new(),
+            override_keywords: Vec::new(),
+            timestamp_clock: TimestampClock::default(),
+            span_mode: SpanMode::default(),
+            event_naming: EventNaming::default(),
+            metadata_fields: MetadataFields::default(),
+            thread_fields: ThreadFields::default(),
+            span_fields: SpanFieldPropagation::default(),
+            span_update_events: false,
+            activity_ids: Arc::new(SeededActivityIds),
+            backend,
+            routes: Vec::new(),
             _m: PhantomData,
         }
     }
-}
 
-impl<Mode> EtwLayerBuilder<Mode>
-where
-    Mode: EventMode,
-{
     /// For advanced scenarios.
     /// Assign a provider ID to the ETW provider rather than use
     /// one generated from the provider name.
@@ -102,6 +496,188 @@ where
         self
     }
 
+    /// Write spans and events whose target starts with `target_prefix` with
+    /// the keyword `kw` instead of the default keyword, so consumers can enable
+    /// a single subsystem. When several prefixes match, the longest one wins.
+    ///
+    /// A single callsite can also choose its own keyword with an `etw.keyword` field.
+    /// See [`EtwLayerBuilder::with_override_keyword`].
+    ///
+    /// ```no_run
+    /// # use tracing_etw::LayerBuilder;
+    /// let builder = LayerBuilder::new("myapp")
+    ///     .with_target_keyword("myapp::db", 0x4)
+    ///     .with_target_keyword("myapp::http", 0x8);
+    /// ```
+    pub fn with_target_keyword(mut self, target_prefix: &str, kw: u64) -> Self {
+        self.target_keywords
+            .retain(|(prefix, _)| prefix != target_prefix);
+        self.target_keywords.push((target_prefix.to_owned(), kw));
+        self
+    }
+
+    /// Allow callsites to choose the keyword `kw` with an `etw.keyword` field, for
+    /// example to move one noisy callsite onto its own keyword bit.
+    ///
+    /// The `etw.keyword` field takes precedence over the target keyword, but only the
+    /// default keyword, the target keywords and the override keywords can be chosen.
+    /// Any other value is ignored, and the span or event keeps its target's keyword,
+    /// so the set of keywords a provider registers stays fixed.
+    ///
+    /// The `etw.level`, `etw.tag` and `etw.opcode` fields likewise override the level,
+    /// event tag and (for events only) opcode. Values that don't fit are ignored.
+    /// None of these fields are written as part of the payload.
+    ///
+    /// ```no_run
+    /// # use tracing_etw::LayerBuilder;
+    /// # use tracing_subscriber::prelude::*;
+    /// let subscriber = tracing_subscriber::registry()
+    ///     .with(LayerBuilder::new("myapp").with_override_keyword(0x100).build());
+    ///
+    /// tracing::subscriber::with_default(subscriber, || {
+    ///     tracing::info!(etw.keyword = 0x100, "noisy");
+    /// });
+    /// ```
+    pub fn with_override_keyword(mut self, kw: u64) -> Self {
+        if !self.override_keywords.contains(&kw) {
+            self.override_keywords.push(kw);
+        }
+        self
+    }
+
+    /// Write spans and events that match `route` to `provider` instead of this one.
+    /// Routes are checked in the order they were added, once per callsite.
+    ///
+    /// The routed provider shares this layer's keywords, filters and span bookkeeping,
+    /// so each span has a single activity ID whichever provider it is written to.
+    ///
+    /// Routing is exclusive: each span or event is written to exactly one provider,
+    /// and every provider writes the same kind of events as this layer. To write
+    /// events both as native events and as Common Schema events, add a layer for
+    /// each to the subscriber instead.
+    ///
+    /// ```no_run
+    /// # use tracing_etw::{LayerBuilder, ProviderRoute, RoutedProvider};
+    /// # use tracing_subscriber::prelude::*;
+    /// let subscriber = tracing_subscriber::registry().with(
+    ///     LayerBuilder::new("myapp")
+    ///         .with_route(
+    ///             ProviderRoute::Target("myapp::audit".to_owned()),
+    ///             RoutedProvider::new("myapp_audit"),
+    ///         )
+    ///         .build(),
+    /// );
+    /// ```
+    pub fn with_route(mut self, route: ProviderRoute, provider: RoutedProvider<Mode>) -> Self {
+        self.routes.push((route, provider));
+        self
+    }
+
+    /// Choose the clock for the timestamp field written with each user_events event.
+    /// Defaults to [`TimestampClock::WallClock`]. Has no effect on ETW or Common Schema events.
+    pub fn with_timestamp_clock(mut self, clock: TimestampClock) -> Self {
+        self.timestamp_clock = clock;
+        self
+    }
+
+    /// Choose when span start and stop events are written.
+    /// Defaults to [`SpanMode::PerEnter`].
+    pub fn with_span_mode(mut self, mode: SpanMode) -> Self {
+        self.span_mode = mode;
+        self
+    }
+
+    /// Choose which span fields are copied onto each event.
+    /// Defaults to [`SpanFieldPropagation::None`].
+    pub fn with_span_fields(mut self, propagation: SpanFieldPropagation) -> Self {
+        self.span_fields = propagation;
+        self
+    }
+
+    /// Write an event each time values are recorded on an existing span, with
+    /// `Span::record`, rather than only writing them with the span's stop event.
+    /// The event has the span's name and activity ID, and only the newly recorded fields.
+    /// Defaults to false.
+    pub fn with_span_update_events(mut self, enabled: bool) -> Self {
+        self.span_update_events = enabled;
+        self
+    }
+
+    /// Choose how activity IDs are generated for spans.
+    /// Defaults to [`SeededActivityIds`].
+    ///
+    /// ```no_run
+    /// # use tracing_etw::{LayerBuilder, RandomActivityIds};
+    /// let builder = LayerBuilder::new("myapp").with_activity_ids(RandomActivityIds);
+    /// ```
+    pub fn with_activity_ids<G>(mut self, generator: G) -> Self
+    where
+        G: ActivityIdGenerator + 'static,
+    {
+        self.activity_ids = Arc::new(generator);
+        self
+    }
+
+    /// Choose how events are named. Defaults to [`EventNaming::CallsiteName`].
+    ///
+    /// ```no_run
+    /// # use tracing_etw::{EventNaming, LayerBuilder};
+    /// let builder = LayerBuilder::new("myapp").with_event_naming(EventNaming::Field("name"));
+    /// ```
+    pub fn with_event_naming(mut self, naming: EventNaming) -> Self {
+        self.event_naming = naming;
+        self
+    }
+
+    /// Write the source file of the callsite with each event and span start.
+    /// See [`MetadataFields`] for how it is written. Defaults to false.
+    pub fn with_file(mut self, enabled: bool) -> Self {
+        self.metadata_fields.file = enabled;
+        self
+    }
+
+    /// Write the source line of the callsite with each event and span start.
+    /// See [`MetadataFields`] for how it is written. Defaults to false.
+    pub fn with_line_number(mut self, enabled: bool) -> Self {
+        self.metadata_fields.line = enabled;
+        self
+    }
+
+    /// Write the module path of the callsite with each event and span start.
+    /// See [`MetadataFields`] for how it is written. Defaults to false.
+    pub fn with_module_path(mut self, enabled: bool) -> Self {
+        self.metadata_fields.module_path = enabled;
+        self
+    }
+
+    /// Write the target of the callsite with each event and span start.
+    /// See [`MetadataFields`] for how it is written. Defaults to false.
+    pub fn with_target(mut self, enabled: bool) -> Self {
+        self.metadata_fields.target = enabled;
+        self
+    }
+
+    /// Write the OS thread ID of the writing thread with each event and span event.
+    /// See [`ThreadFields`] for how it is written. Defaults to false.
+    pub fn with_thread_id(mut self, enabled: bool) -> Self {
+        self.thread_fields.thread_id = enabled;
+        self
+    }
+
+    /// Write the name of the writing thread, if it has one, with each event and span event.
+    /// See [`ThreadFields`] for how it is written. Defaults to false.
+    pub fn with_thread_name(mut self, enabled: bool) -> Self {
+        self.thread_fields.thread_name = enabled;
+        self
+    }
+
+    /// Write the process ID with each event and span event.
+    /// See [`ThreadFields`] for how it is written. Defaults to false.
+    pub fn with_process_id(mut self, enabled: bool) -> Self {
+        self.thread_fields.process_id = enabled;
+        self
+    }
+
     /// For advanced scenarios.
     /// Set the ETW provider group to join this provider to.
     #[cfg(any(target_os = "windows", doc))]
@@ -119,136 +695,503 @@ where
         self
     }
 
-    fn validate_config(&self) {
-        match &self.provider_group {
-            native::ProviderGroup::Unset => (),
-            native::ProviderGroup::Windows(guid) => {
-                assert_ne!(guid, &Guid::zero(), "Provider GUID must not be zeroes");
+    /// Check the builder's configuration. `build` has always accepted any provider
+    /// name, ID and keyword, so unless `strict` is set only the group is checked.
+    /// Custom backends register nothing with the OS, so their names, IDs and groups
+    /// are not checked.
+    pub(crate) fn validate_config(&self, strict: bool) -> Result<(), BuildError> {
+        if Mode::REGISTERS_PROVIDER {
+            validate_group(&self.provider_group)?;
+            for (_, provider) in &self.routes {
+                validate_group(&provider.group)?;
             }
-            native::ProviderGroup::Linux(name) => {
-                assert!(
-                    eventheader_dynamic::ProviderOptions::is_valid_option_value(name),
-                    "Provider names must be lower case ASCII or numeric digits"
-                );
+        }
+
+        if !strict {
+            return Ok(());
+        }
+
+        if Mode::REGISTERS_PROVIDER {
+            validate_identity(&self.provider_name, &self.provider_id, &self.provider_group)?;
+            for (_, provider) in &self.routes {
+                validate_identity(&provider.name, &provider.id, &provider.group)?;
             }
         }
 
-        #[cfg(all(target_os = "linux"))]
-        if self
-            .provider_name
-            .contains(|f: char| !f.is_ascii_alphanumeric())
+        if self.default_keyword == 0
+            || self.target_keywords.iter().any(|(_, kw)| *kw == 0)
+            || self.override_keywords.contains(&0)
         {
-            // The perf command is very particular about the provider names it accepts.
-            // The Linux kernel itself cares less, and other event consumers should also presumably not need this check.
-            //panic!("Linux provider names must be ASCII alphanumeric");
+            return Err(BuildError::ZeroKeyword);
+        }
+
+        Ok(())
+    }
+
+    /// Validate the configuration and build the layer. With `strict`, every
+    /// configuration error and a provider that could not be registered are errors,
+    /// as the `try_build` methods report them.
+    fn build_checked<S>(
+        &self,
+        settings: FilterSettings,
+        strict: bool,
+    ) -> Result<EtwLayer<S, Mode::Provider>, BuildError>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        self.validate_config(strict)?;
+
+        let (layer, registration) = self.build_layer(settings);
+        if strict {
+            registration?;
+        }
+
+        Ok(layer)
+    }
+
+    /// Build the layer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the provider group is invalid. Other configuration errors and
+    /// registration failures are ignored, and the layer may not write any events.
+    /// Use [`EtwLayerBuilder::try_build`] to handle them.
+    #[cfg(feature = "global_filter")]
+    pub fn build<S>(self) -> EtwLayer<S, Mode::Provider>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        expect_valid(self.build_checked(self.fixed_settings(), false))
+    }
+
+    /// Like [`EtwLayerBuilder::build`], but returns an error
+    /// for an invalid configuration or if the provider could not be registered.
+    #[cfg(feature = "global_filter")]
+    pub fn try_build<S>(self) -> Result<EtwLayer<S, Mode::Provider>, BuildError>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        self.build_checked(self.fixed_settings(), true)
+    }
+
+    /// Add this provider's name and group name to `targets`, so spans and events
+    /// with either as their target are always passed to the layer.
+    #[cfg(not(feature = "global_filter"))]
+    fn build_target_filter(&self, targets: Targets) -> Targets {
+        self.always_enabled_targets()
+            .into_iter()
+            .fold(targets, |targets, name| {
+                targets.with_target(name, LevelFilter::TRACE)
+            })
+    }
+
+    /// The provider name, and the group name if it has one, of this and every routed provider.
+    #[cfg(not(feature = "global_filter"))]
+    fn always_enabled_targets(&self) -> Vec<String> {
+        let mut names = vec![self.provider_name.clone()];
+
+        match self.provider_group {
+            ProviderGroup::Windows(_guid) => {}
+            ProviderGroup::Linux(ref name) => names.push(name.to_string()),
+            _ => {}
+        }
+
+        for (_, provider) in &self.routes {
+            names.push(provider.name.clone());
+            if let ProviderGroup::Linux(ref name) = provider.group {
+                names.push(name.to_string());
+            }
+        }
+
+        names
+    }
+
+    #[cfg(not(feature = "global_filter"))]
+    fn single_target(target: &'static str) -> Targets {
+        if target.is_empty() {
+            Targets::new()
+        } else {
+            Targets::new().with_target(target, LevelFilter::TRACE)
+        }
+    }
+
+    fn keyword_map(&self) -> KeywordMap {
+        KeywordMap::new(
+            self.default_keyword,
+            &self.target_keywords,
+            &self.override_keywords,
+        )
+    }
+
+    /// Settings for a layer built without a [`ReloadHandle`], which never change.
+    fn fixed_settings(&self) -> FilterSettings {
+        FilterSettings::new(self.keyword_map())
+    }
+
+    fn build_layer<S>(
+        &self,
+        settings: FilterSettings,
+    ) -> (EtwLayer<S, Mode::Provider>, Result<(), BuildError>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let keywords = settings.keywords();
+        let (default, mut registration) = Mode::new_provider(
+            &self.backend,
+            &self.provider_name,
+            &self.provider_id,
+            &self.provider_group,
+            &keywords,
+            self.timestamp_clock,
+        );
+        let routes = self
+            .routes
+            .iter()
+            .map(|(route, provider)| {
+                let (provider, routed_registration) = Mode::new_provider(
+                    &provider.backend,
+                    &provider.name,
+                    &provider.id,
+                    &provider.group,
+                    &keywords,
+                    self.timestamp_clock,
+                );
+                // Report the first provider that failed to register
+                if registration.is_ok() {
+                    registration = routed_registration;
+                }
+                (route.clone(), provider)
+            })
+            .collect();
+
+        (
+            EtwLayer::<S, Mode::Provider> {
+                id: NEXT_LAYER_ID.fetch_add(1, Ordering::Relaxed),
+                providers: Arc::new(Providers::new(default, routes)),
+                settings,
+                span_mode: self.span_mode,
+                timestamp_clock: self.timestamp_clock,
+                event_naming: self.event_naming.clone(),
+                metadata_fields: self.metadata_fields,
+                thread_fields: self.thread_fields,
+                span_fields: self.span_fields,
+                span_update_events: self.span_update_events,
+                activity_ids: self.activity_ids.clone(),
+                with_context: WithContext(EtwLayer::<S, Mode::Provider>::get_context),
+                _p: PhantomData,
+            },
+            registration,
+        )
+    }
+
+    #[cfg(not(feature = "global_filter"))]
+    fn build_filter<S, P>(&self, layer: &EtwLayer<S, P>) -> EtwFilter<S, P>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+        P: EventWriter + 'static,
+    {
+        EtwFilter::<S, _> {
+            providers: layer.providers.clone(),
+            settings: layer.settings.clone(),
+            _p: PhantomData,
         }
     }
 
-    #[cfg(feature = "global_filter")]
-    pub fn build<S>(self) -> EtwLayer<S, Mode>
+    #[cfg(not(feature = "global_filter"))]
+    #[allow(clippy::type_complexity)]
+    fn build_filtered<S>(
+        &self,
+        strict: bool,
+    ) -> Result<Filtered<EtwLayer<S, Mode::Provider>, EtwFilter<S, Mode::Provider>, S>, BuildError>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let layer = self.build_checked(self.fixed_settings(), strict)?;
+
+        let filter = self.build_filter(&layer);
+
+        Ok(layer.with_filter(filter))
+    }
+
+    #[cfg(not(feature = "global_filter"))]
+    #[allow(clippy::type_complexity)]
+    fn build_filtered_with_targets<S>(
+        &self,
+        targets: Targets,
+        strict: bool,
+    ) -> Result<
+        Filtered<EtwLayer<S, Mode::Provider>, And<EtwFilter<S, Mode::Provider>, Targets, S>, S>,
+        BuildError,
+    >
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let layer = self.build_checked(self.fixed_settings(), strict)?;
+
+        let filter = self.build_filter(&layer);
+
+        let targets = self.build_target_filter(targets);
+
+        Ok(layer.with_filter(filter.and(targets)))
+    }
+
+    #[cfg(not(feature = "global_filter"))]
+    #[allow(clippy::type_complexity)]
+    fn build_filtered_with_reload<S>(
+        &self,
+        strict: bool,
+    ) -> Result<
+        (
+            Filtered<EtwLayer<S, Mode::Provider>, EtwFilter<S, Mode::Provider>, S>,
+            ReloadHandle,
+        ),
+        BuildError,
+    >
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let (settings, handle) =
+            FilterSettings::reloadable(self.keyword_map(), self.always_enabled_targets());
+
+        let layer = self.build_checked(settings, strict)?;
+
+        let filter = self.build_filter(&layer);
+
+        Ok((layer.with_filter(filter), handle))
+    }
+
+    /// Build the layer, only passing it spans and events from this provider's
+    /// name, its group name, and `target`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the provider group is invalid. Other configuration errors and
+    /// registration failures are ignored, and the layer may not write any events.
+    /// Use [`EtwLayerBuilder::try_build_with_target`] to handle them.
+    #[cfg(not(feature = "global_filter"))]
+    #[allow(clippy::type_complexity)]
+    pub fn build_with_target<S>(
+        self,
+        target: &'static str,
+    ) -> Filtered<EtwLayer<S, Mode::Provider>, And<EtwFilter<S, Mode::Provider>, Targets, S>, S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        self.build_with_targets(Self::single_target(target))
+    }
+
+    /// Like [`EtwLayerBuilder::build_with_target`], but returns an error
+    /// for an invalid configuration or if the provider could not be registered.
+    #[cfg(not(feature = "global_filter"))]
+    #[allow(clippy::type_complexity)]
+    pub fn try_build_with_target<S>(
+        self,
+        target: &'static str,
+    ) -> Result<
+        Filtered<EtwLayer<S, Mode::Provider>, And<EtwFilter<S, Mode::Provider>, Targets, S>, S>,
+        BuildError,
+    >
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        self.try_build_with_targets(Self::single_target(target))
+    }
+
+    /// Build the layer, only passing it spans and events from this provider's
+    /// name, its group name, and the targets and levels enabled by `targets`.
+    ///
+    /// `Targets` can be parsed from a directive string such as
+    /// `myapp=info,myapp::db=trace,hyper=warn`.
+    ///
+    /// ```no_run
+    /// # use tracing_etw::LayerBuilder;
+    /// # use tracing_subscriber::prelude::*;
+    /// let targets = "myapp=info,myapp::db=trace,hyper=warn".parse().unwrap();
+    /// let subscriber = tracing_subscriber::registry()
+    ///     .with(LayerBuilder::new("myapp").build_with_targets(targets));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the provider group is invalid. Other configuration errors and
+    /// registration failures are ignored, and the layer may not write any events.
+    /// Use [`EtwLayerBuilder::try_build_with_targets`] to handle them.
+    #[cfg(not(feature = "global_filter"))]
+    #[allow(clippy::type_complexity)]
+    pub fn build_with_targets<S>(
+        self,
+        targets: Targets,
+    ) -> Filtered<EtwLayer<S, Mode::Provider>, And<EtwFilter<S, Mode::Provider>, Targets, S>, S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        expect_valid(self.build_filtered_with_targets(targets, false))
+    }
+
+    /// Like [`EtwLayerBuilder::build_with_targets`], but returns an error
+    /// for an invalid configuration or if the provider could not be registered.
+    #[cfg(not(feature = "global_filter"))]
+    #[allow(clippy::type_complexity)]
+    pub fn try_build_with_targets<S>(
+        self,
+        targets: Targets,
+    ) -> Result<
+        Filtered<EtwLayer<S, Mode::Provider>, And<EtwFilter<S, Mode::Provider>, Targets, S>, S>,
+        BuildError,
+    >
     where
         S: Subscriber + for<'a> LookupSpan<'a>,
     {
-        self.validate_config();
-
-        EtwLayer::<S, native::Provider> {
-            provider: native::Provider::new(
-                &self.provider_name,
-                &self.provider_id,
-                &self.provider_group,
-                self.default_keyword,
-            ),
-            default_keyword: self.default_keyword,
-            _p: PhantomData,
-        }
+        self.build_filtered_with_targets(targets, true)
     }
 
-    fn build_target_filter(&self, target: &'static str) -> Targets {
-        let mut targets = Targets::new().with_target(&self.provider_name, LevelFilter::TRACE);
-
-        match self.provider_group {
-            ProviderGroup::Windows(_guid) => {}
-            ProviderGroup::Linux(ref name) => {
-                targets = targets.with_target(name.clone(), LevelFilter::TRACE);
-            }
-            _ => {}
-        }
-
-        if !target.is_empty() {
-            targets = targets.with_target(target, LevelFilter::TRACE)
-        }
-
-        targets
+    /// Build the layer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the provider group is invalid. Other configuration errors and
+    /// registration failures are ignored, and the layer may not write any events.
+    /// Use [`EtwLayerBuilder::try_build`] to handle them.
+    #[cfg(not(feature = "global_filter"))]
+    #[allow(clippy::type_complexity)]
+    pub fn build<S>(self) -> Filtered<EtwLayer<S, Mode::Provider>, EtwFilter<S, Mode::Provider>, S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        expect_valid(self.build_filtered(false))
     }
 
-    fn build_layer<S>(&self) -> EtwLayer<S, Mode::Provider>
+    /// Like [`EtwLayerBuilder::build`], but returns an error
+    /// for an invalid configuration or if the provider could not be registered.
+    #[cfg(not(feature = "global_filter"))]
+    #[allow(clippy::type_complexity)]
+    pub fn try_build<S>(
+        self,
+    ) -> Result<Filtered<EtwLayer<S, Mode::Provider>, EtwFilter<S, Mode::Provider>, S>, BuildError>
     where
         S: Subscriber + for<'a> LookupSpan<'a>,
-        Mode::Provider: EventWriter + 'static,
     {
-        EtwLayer::<S, Mode::Provider> {
-            provider: Mode::Provider::new(
-                &self.provider_name,
-                &self.provider_id,
-                &self.provider_group,
-                self.default_keyword,
-            ),
-            default_keyword: self.default_keyword,
-            _p: PhantomData,
-        }
+        self.build_filtered(true)
     }
 
-    fn build_filter<S, P>(&self, provider: Pin<Arc<P>>) -> EtwFilter<S, P>
+    /// Build the layer, along with a [`ReloadHandle`] that can change its keywords,
+    /// targets and levels while it is in use.
+    ///
+    /// ```no_run
+    /// # use tracing_etw::LayerBuilder;
+    /// # use tracing_subscriber::prelude::*;
+    /// let (layer, handle) = LayerBuilder::new("myapp").build_with_reload();
+    /// tracing_subscriber::registry().with(layer).init();
+    ///
+    /// handle.set_targets(Some("myapp=info".parse().unwrap()));
+    /// // Later, while investigating a problem
+    /// handle.set_targets(Some("myapp=trace".parse().unwrap()));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the provider group is invalid. Other configuration errors and
+    /// registration failures are ignored, and the layer may not write any events.
+    /// Use [`EtwLayerBuilder::try_build_with_reload`] to handle them.
+    #[cfg(not(feature = "global_filter"))]
+    #[allow(clippy::type_complexity)]
+    pub fn build_with_reload<S>(
+        self,
+    ) -> (
+        Filtered<EtwLayer<S, Mode::Provider>, EtwFilter<S, Mode::Provider>, S>,
+        ReloadHandle,
+    )
     where
         S: Subscriber + for<'a> LookupSpan<'a>,
-        P: EventWriter + 'static,
     {
-        EtwFilter::<S, _> {
-            provider,
-            default_keyword: self.default_keyword,
-            _p: PhantomData,
-        }
+        expect_valid(self.build_filtered_with_reload(false))
     }
 
+    /// Like [`EtwLayerBuilder::build_with_reload`], but returns an error
+    /// for an invalid configuration or if the provider could not be registered.
     #[cfg(not(feature = "global_filter"))]
-    pub fn build_with_target<S>(
+    #[allow(clippy::type_complexity)]
+    pub fn try_build_with_reload<S>(
         self,
-        target: &'static str,
-    ) -> Filtered<EtwLayer<S, Mode::Provider>, And<EtwFilter<S, Mode::Provider>, Targets, S>, S>
+    ) -> Result<
+        (
+            Filtered<EtwLayer<S, Mode::Provider>, EtwFilter<S, Mode::Provider>, S>,
+            ReloadHandle,
+        ),
+        BuildError,
+    >
     where
         S: Subscriber + for<'a> LookupSpan<'a>,
-        Mode::Provider: EventWriter + 'static,
     {
-        self.validate_config();
+        self.build_filtered_with_reload(true)
+    }
+}
 
-        let layer = self.build_layer();
+/// Unwrap the result of a non-strict build, whose only errors are ones `build` has always panicked on.
+fn expect_valid<T>(result: Result<T, BuildError>) -> T {
+    result.unwrap_or_else(|e| panic!("{}", e))
+}
 
-        let filter = self.build_filter(layer.provider.clone());
+fn validate_group(group: &ProviderGroup) -> Result<(), BuildError> {
+    match group {
+        ProviderGroup::Unset => (),
+        ProviderGroup::Windows(guid) => {
+            if guid == &Guid::zero() {
+                return Err(BuildError::ZeroGroupId);
+            }
+        }
+        ProviderGroup::Linux(name) => {
+            if !eventheader_dynamic::ProviderOptions::is_valid_option_value(name) {
+                return Err(BuildError::InvalidGroupName(name.to_string()));
+            }
+        }
+    }
 
-        let targets = self.build_target_filter(target);
+    Ok(())
+}
 
-        layer.with_filter(filter.and(targets))
+/// The provider checks only made by the `try_build` methods.
+/// The group only limits the name length on Linux.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn validate_identity(name: &str, id: &Guid, group: &ProviderGroup) -> Result<(), BuildError> {
+    if name.is_empty() || name.contains('\0') {
+        return Err(BuildError::InvalidProviderName(name.to_owned()));
     }
 
-    #[cfg(not(feature = "global_filter"))]
-    pub fn build<S>(self) -> Filtered<EtwLayer<S, Mode::Provider>, EtwFilter<S, Mode::Provider>, S>
-    where
-        S: Subscriber + for<'a> LookupSpan<'a>,
-        Mode::Provider: EventWriter + 'static,
-    {
-        self.validate_config();
+    #[cfg(target_os = "windows")]
+    if name.len() >= 32768 {
+        return Err(BuildError::InvalidProviderName(name.to_owned()));
+    }
 
-        let layer = self.build_layer();
+    #[cfg(target_os = "linux")]
+    {
+        // The perf command is very particular about the provider names it accepts.
+        // The Linux kernel itself cares less, and other event consumers should also presumably not need this check.
+        if name.contains(|f: char| !f.is_ascii_alphanumeric() && f != '_') {
+            return Err(BuildError::InvalidProviderName(name.to_owned()));
+        }
 
-        let filter = self.build_filter(layer.provider.clone());
+        // Tracepoint names are the provider name, the group name and a level/keyword suffix,
+        // and must fit in 256 bytes.
+        let group_len = match group {
+            ProviderGroup::Linux(name) => name.len(),
+            _ => 0,
+        };
+        if name.len() + group_len >= 234 {
+            return Err(BuildError::InvalidProviderName(name.to_owned()));
+        }
+    }
 
-        layer.with_filter(filter)
+    if id == &Guid::zero() {
+        return Err(BuildError::ZeroProviderId);
     }
+
+    Ok(())
 }
 
 pub struct EtwFilter<S, P> {
-    provider: Pin<Arc<P>>,
-    default_keyword: u64,
+    providers: Arc<Providers<P>>,
+    settings: FilterSettings,
     _p: PhantomData<S>,
 }
 
@@ -261,11 +1204,15 @@ where
         &self,
         metadata: &'static tracing::Metadata<'static>,
     ) -> tracing::subscriber::Interest {
-        if P::supports_enable_callback() {
-            if self
-                .provider
-                .enabled(map_level(metadata.level()), self.default_keyword)
-            {
+        if !self.settings.allows(metadata) {
+            return tracing::subscriber::Interest::never();
+        }
+
+        if P::supports_enable_callback() && !has_header_overrides(metadata) {
+            if self.providers.route(metadata).enabled(
+                map_level(metadata.level()),
+                self.settings.keyword(metadata.target()),
+            ) {
                 tracing::subscriber::Interest::always()
             } else {
                 tracing::subscriber::Interest::never()
@@ -282,8 +1229,20 @@ where
         metadata: &tracing::Metadata<'_>,
         _cx: &tracing_subscriber::layer::Context<'_, S>,
     ) -> bool {
-        self.provider
-            .enabled(map_level(metadata.level()), self.default_keyword)
+        if !self.settings.allows(metadata) {
+            return false;
+        }
+
+        // The level and keyword may be overridden by field values, which aren't known yet.
+        // Events are checked again in event_enabled; spans are always enabled.
+        if has_header_overrides(metadata) {
+            return true;
+        }
+
+        self.providers.route(metadata).enabled(
+            map_level(metadata.level()),
+            self.settings.keyword(metadata.target()),
+        )
     }
 
     fn event_enabled(
@@ -291,17 +1250,244 @@ where
         event: &tracing::Event<'_>,
         _cx: &tracing_subscriber::layer::Context<'_, S>,
     ) -> bool {
-        self.provider
-            .enabled(map_level(event.metadata().level()), self.default_keyword)
+        let metadata = event.metadata();
+
+        let mut overrides = HeaderOverrides::default();
+        if has_header_overrides(metadata) {
+            event.record(&mut overrides);
+        }
+
+        self.providers.route(metadata).enabled(
+            overrides
+                .level
+                .unwrap_or_else(|| map_level(metadata.level())),
+            self.settings
+                .keyword_or_override(metadata.target(), overrides.keyword),
+        )
     }
 }
 
+fn has_header_overrides(metadata: &tracing::Metadata<'_>) -> bool {
+    metadata
+        .fields()
+        .iter()
+        .any(|field| is_reserved_field(field.name()))
+}
+
 pub struct EtwLayer<S, P> {
-    provider: Pin<Arc<P>>,
-    default_keyword: u64,
+    id: usize,
+    providers: Arc<Providers<P>>,
+    settings: FilterSettings,
+    span_mode: SpanMode,
+    timestamp_clock: TimestampClock,
+    event_naming: EventNaming,
+    metadata_fields: MetadataFields,
+    thread_fields: ThreadFields,
+    span_fields: SpanFieldPropagation,
+    span_update_events: bool,
+    activity_ids: Arc<dyn ActivityIdGenerator>,
+    with_context: WithContext,
     _p: PhantomData<S>,
 }
 
+impl<S, P> EtwLayer<S, P>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn span_record<'a>(
+        &self,
+        span: &tracing_subscriber::registry::SpanRef<'_, S>,
+        data: &'a EtwLayerData,
+    ) -> SpanRecord<'a> {
+        let metadata = span.metadata();
+
+        SpanRecord {
+            name: metadata.name(),
+            metadata,
+            span_id: span.id().into_u64(),
+            parent_span_id: span.parent().map_or(0, |parent| parent.id().into_u64()),
+            activity_id: &data.activity_id,
+            related_activity_id: data.related_activity_id.as_ref(),
+            trace_context: &data.trace_context,
+            level: data
+                .overrides
+                .level
+                .unwrap_or_else(|| map_level(metadata.level())),
+            keyword: self
+                .settings
+                .keyword_or_override(metadata.target(), data.overrides.keyword),
+            event_tag: data.overrides.tag.unwrap_or(0),
+            fields: Fields::new(&data.fields),
+            metadata_fields: self.metadata_fields,
+            thread_fields: self.thread_fields,
+            monotonic_timestamp: self.monotonic_timestamp(),
+        }
+    }
+
+    /// Read the monotonic clock, if the providers write monotonic timestamps.
+    fn monotonic_timestamp(&self) -> Option<u64> {
+        if self.timestamp_clock != TimestampClock::Monotonic {
+            return None;
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            Some(native::monotonic_nanos())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            None
+        }
+    }
+}
+
+impl<S, P> EtwLayer<S, P>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    P: EventWriter + 'static,
+{
+    /// Start tracking a new span in this layer, writing its start event if spans
+    /// start when they are created.
+    fn new_span_state(
+        &self,
+        span: &tracing_subscriber::registry::SpanRef<'_, S>,
+        data: &mut EtwLayerData,
+    ) where
+        P: EventWriter + 'static,
+    {
+        let mut state = SpanState::new();
+        let provider = self.providers.route(span.metadata());
+        let record = self.span_record(span, data);
+
+        // The filter passes spans with header overrides without knowing their values,
+        // so the overridden level and keyword are checked once they are.
+        if has_header_overrides(span.metadata()) && !provider.enabled(record.level, record.keyword)
+        {
+            state.enabled = false;
+        } else if self.span_mode == SpanMode::Lifecycle {
+            state.start_time = SystemTime::now();
+            state.started = true;
+
+            provider.span_start(&record, state.start_time);
+        }
+
+        data.set_state(self.id, state);
+    }
+
+    fn get_context(
+        dispatch: &tracing::Dispatch,
+        id: &span::Id,
+        f: &mut dyn FnMut(&[u8; 16], &TraceContext),
+    ) {
+        let subscriber = if let Some(subscriber) = dispatch.downcast_ref::<S>() {
+            subscriber
+        } else {
+            return;
+        };
+
+        if let Some(span) = subscriber.span(id) {
+            if let Some(data) = span.extensions().get::<EtwLayerData>() {
+                f(&data.activity_id, &data.trace_context);
+            }
+        }
+    }
+
+    fn append_span_fields(
+        &self,
+        span: &tracing_subscriber::registry::SpanRef<'_, S>,
+        fields: &mut EventFields,
+    ) {
+        let depth = match self.span_fields {
+            SpanFieldPropagation::None => return,
+            SpanFieldPropagation::CurrentSpan => 1,
+            SpanFieldPropagation::FullScope => usize::MAX,
+        };
+
+        for span in span.scope().take(depth) {
+            let extensions = span.extensions();
+            let data = if let Some(data) = extensions.get::<EtwLayerData>() {
+                data
+            } else {
+                continue;
+            };
+
+            let names = data
+                .prefixed_names
+                .get_or_init(|| prefixed_field_names(span.metadata()));
+
+            for (f, name) in data.fields.iter().zip(names.iter()) {
+                let value = if let Some(value) = &f.value {
+                    value
+                } else {
+                    continue;
+                };
+
+                if fields.fields.iter().any(|existing| existing.field == *name) {
+                    continue;
+                }
+
+                fields.push(name, value);
+            }
+        }
+    }
+
+    fn write_event(
+        &self,
+        event: &tracing::Event<'_>,
+        fields: &mut EventFields,
+        timestamp: std::time::SystemTime,
+        monotonic_timestamp: Option<u64>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let metadata = event.metadata();
+
+        let mut overrides = HeaderOverrides::default();
+        overrides.take_from(&mut fields.fields);
+
+        let event_field_count = fields.fields.len();
+        let current_span = ctx.event_span(event);
+        if let Some(span) = &current_span {
+            self.append_span_fields(span, fields);
+        }
+
+        // Only the event's own fields are used for its name
+        let name = self
+            .event_naming
+            .event_name(metadata, &fields.fields[..event_field_count]);
+
+        let extensions = current_span.as_ref().map(|span| span.extensions());
+        let data = extensions
+            .as_ref()
+            .and_then(|ext| ext.get::<EtwLayerData>());
+
+        self.providers.route(metadata).write_record(&EventRecord {
+            name: &name,
+            metadata,
+            timestamp,
+            monotonic_timestamp,
+            span_id: current_span.as_ref().map_or(0, |span| span.id().into_u64()),
+            parent_span_id: current_span
+                .as_ref()
+                .and_then(|span| span.parent())
+                .map_or(0, |parent| parent.id().into_u64()),
+            activity_id: data.map(|data| &data.activity_id),
+            related_activity_id: data.and_then(|data| data.related_activity_id.as_ref()),
+            trace_context: data.map(|data| &data.trace_context),
+            level: overrides
+                .level
+                .unwrap_or_else(|| map_level(metadata.level())),
+            keyword: self
+                .settings
+                .keyword_or_override(metadata.target(), overrides.keyword),
+            event_tag: overrides.tag.unwrap_or(0),
+            opcode: overrides.opcode.unwrap_or(0),
+            fields: Fields::new(&fields.fields),
+            metadata_fields: self.metadata_fields,
+            thread_fields: self.thread_fields,
+        });
+    }
+}
+
 impl<S, P> Layer<S> for EtwLayer<S, P>
 where
     S: Subscriber + for<'a> LookupSpan<'a>,
@@ -320,11 +1506,15 @@ where
         &self,
         metadata: &'static tracing::Metadata<'static>,
     ) -> tracing::subscriber::Interest {
-        if ProviderWrapper::supports_enable_callback() {
-            if self
-                .provider
-                .enabled(map_level(metadata.level()), self.default_keyword)
-            {
+        if !self.settings.allows(metadata) {
+            return tracing::subscriber::Interest::never();
+        }
+
+        if P::supports_enable_callback() && !has_header_overrides(metadata) {
+            if self.providers.route(metadata).enabled(
+                map_level(metadata.level()),
+                self.settings.keyword(metadata.target()),
+            ) {
                 tracing::subscriber::Interest::always()
             } else {
                 tracing::subscriber::Interest::never()
@@ -342,40 +1532,54 @@ where
         metadata: &tracing::Metadata<'_>,
         _ctx: tracing_subscriber::layer::Context<'_, S>,
     ) -> bool {
-        self.provider
-            .enabled(map_level(metadata.level()), self.default_keyword)
+        if !self.settings.allows(metadata) {
+            return false;
+        }
+
+        // The level and keyword may be overridden by field values, which aren't known yet.
+        // Events are checked again in event_enabled; spans are always enabled.
+        if has_header_overrides(metadata) {
+            return true;
+        }
+
+        self.providers.route(metadata).enabled(
+            map_level(metadata.level()),
+            self.settings.keyword(metadata.target()),
+        )
     }
 
     #[cfg(feature = "global_filter")]
     fn event_enabled(
         &self,
-        _event: &tracing::Event<'_>,
+        event: &tracing::Event<'_>,
         _ctx: tracing_subscriber::layer::Context<'_, S>,
     ) -> bool {
-        self.provider
-            .enabled(map_level(event.metadata().level()), self.default_keyword)
+        let metadata = event.metadata();
+
+        let mut overrides = HeaderOverrides::default();
+        if has_header_overrides(metadata) {
+            event.record(&mut overrides);
+        }
+
+        self.providers.route(metadata).enabled(
+            overrides
+                .level
+                .unwrap_or_else(|| map_level(metadata.level())),
+            self.settings
+                .keyword_or_override(metadata.target(), overrides.keyword),
+        )
     }
 
     fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
         let timestamp = std::time::SystemTime::now();
+        let monotonic_timestamp = self.monotonic_timestamp();
 
-        let current_span = ctx
-            .event_span(event)
-            .map(|evt| evt.id())
-            .map_or(0, |id| (id.into_u64()));
-        let parent_span = ctx
-            .event_span(event)
-            .map_or(0, |evt| evt.parent().map_or(0, |p| p.id().into_u64()));
+        let metadata = event.metadata();
 
-        self.provider.as_ref().write_record(
-            timestamp,
-            current_span,
-            parent_span,
-            event.metadata().name(),
-            map_level(event.metadata().level()),
-            self.default_keyword,
-            event,
-        );
+        EventFields::with(metadata, |fields| {
+            event.record(&mut fields.visitor());
+            self.write_event(event, fields, timestamp, monotonic_timestamp, ctx);
+        });
     }
 
     fn on_new_span(
@@ -390,72 +1594,134 @@ where
             return;
         };
 
-        if span.extensions().get::<EtwLayerData>().is_some() {
+        if let Some(data) = span.extensions_mut().get_mut::<EtwLayerData>() {
+            // Another layer in this subscriber already created the span's data
+            self.new_span_state(&span, data);
             return;
         }
 
         let metadata = span.metadata();
 
-        let parent_span_id = if attrs.is_contextual() {
-            attrs.parent().map_or(0, |id| id.into_u64())
+        let mut fields = new_fields(metadata);
+        attrs.values().record(&mut ValueVisitor::new(&mut fields));
+        let mut overrides = HeaderOverrides::default();
+        overrides.take_from(&mut fields);
+        let remote = RemoteParent::take_from(&mut fields);
+
+        let parent_data = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<EtwLayerData>()
+                .map(|data| (data.activity_id, data.trace_context))
+        });
+
+        let (activity_id, related_activity_id, trace_context) = if let Some(trace_context) =
+            otel::trace_context(&span)
+        {
+            (
+                otel::activity_id(&trace_context.trace_id, &trace_context.span_id),
+                trace_context.parent_span_id.map(|parent_span_id| {
+                    otel::activity_id(&trace_context.trace_id, &parent_span_id)
+                }),
+                trace_context,
+            )
         } else {
-            0
+            let activity_id = self.activity_ids.activity_id(id.into_u64(), metadata);
+
+            // A context imported from another process takes the place of the local parent
+            let trace_context = if let Some((trace_id, parent_span_id, sampled)) = remote.trace {
+                TraceContext {
+                    trace_id,
+                    span_id: new_span_id(),
+                    parent_span_id: Some(parent_span_id),
+                    sampled,
+                }
+            } else {
+                TraceContext {
+                    trace_id: parent_data
+                        .map(|(_, parent)| parent.trace_id)
+                        .unwrap_or_else(new_trace_id),
+                    span_id: new_span_id(),
+                    parent_span_id: parent_data.map(|(_, parent)| parent.span_id),
+                    sampled: parent_data.map_or(true, |(_, parent)| parent.sampled),
+                }
+            };
+
+            (
+                activity_id,
+                remote
+                    .activity_id
+                    .or_else(|| parent_data.map(|(parent_activity_id, _)| parent_activity_id)),
+                trace_context,
+            )
         };
 
-        let n = metadata.fields().len();
-
-        let mut data = {
-            let mut v: Vec<FieldValueIndex> = Vec::with_capacity(n);
-            v.resize_with(n, Default::default);
+        let mut data = EtwLayerData {
+            owner: self.id,
+            fields,
+            prefixed_names: once_cell::sync::OnceCell::new(),
+            activity_id,
+            related_activity_id,
+            trace_context,
+            overrides,
+            layers: Vec::new(),
+        };
 
-            let mut i = 0;
-            for field in metadata.fields().iter() {
-                v[i].field = field.name();
-                v[i].value = ValueTypes::None;
-                v[i].sort_index = i as u8;
-                i += 1;
-            }
+        self.new_span_state(&span, &mut data);
 
-            let mut indexes: [u8; 32] = [
-                0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22,
-                23, 24, 25, 26, 27, 28, 29, 30, 31,
-            ];
+        // This will unfortunately box data. It would be ideal if we could avoid this second heap allocation
+        // by packing everything into a single alloc.
+        span.extensions_mut().replace(data);
+    }
 
-            indexes[0..n].sort_by_key(|idx| v[v[*idx as usize].sort_index as usize].field);
+    unsafe fn downcast_raw(&self, id: std::any::TypeId) -> Option<*const ()> {
+        if id == std::any::TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else if id == std::any::TypeId::of::<WithContext>() {
+            Some(&self.with_context as *const WithContext as *const ())
+        } else {
+            None
+        }
+    }
 
-            i = 0;
-            for f in &mut v {
-                f.sort_index = indexes[i];
-                i += 1;
-            }
+    fn on_follows_from(
+        &self,
+        id: &span::Id,
+        follows: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let (span, follows) = if let (Some(span), Some(follows)) = (ctx.span(id), ctx.span(follows))
+        {
+            (span, follows)
+        } else {
+            return;
+        };
 
-            EtwLayerData {
-                fields: v.into_boxed_slice(),
-                activity_id: *GLOBAL_ACTIVITY_SEED,
-                related_activity_id: *GLOBAL_ACTIVITY_SEED,
-                start_time: SystemTime::UNIX_EPOCH,
+        let link = if let Some(data) = follows.extensions().get::<EtwLayerData>() {
+            SpanLink {
+                activity_id: data.activity_id,
+                trace_context: data.trace_context,
             }
+        } else {
+            return;
         };
 
-        let (_, half) = data.activity_id.split_at_mut(8);
-        half.copy_from_slice(&id.into_u64().to_le_bytes());
-
-        data.activity_id[0] = 1;
-        data.related_activity_id[0] = if parent_span_id != 0 {
-            let (_, half) = data.related_activity_id.split_at_mut(8);
-            half.copy_from_slice(&parent_span_id.to_le_bytes());
-            1
+        let extensions = span.extensions();
+        let data = if let Some(data) = extensions.get::<EtwLayerData>() {
+            data
         } else {
-            0
+            return;
         };
 
-        attrs.values().record(&mut ValueVisitor {
-            fields: &mut data.fields,
-        });
+        if !data.state(self.id).enabled {
+            return;
+        }
 
-        // This will unfortunately box data. It would be ideal if we could avoid this second heap allocation
-        // by packing everything into a single alloc.
-        span.extensions_mut().replace(data);
+        self.providers.route(span.metadata()).span_link(
+            &self.span_record(&span, data),
+            &link,
+            SystemTime::now(),
+        );
     }
 
     fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
@@ -468,8 +1734,6 @@ where
             return;
         };
 
-        let metadata = span.metadata();
-
         let mut extensions = span.extensions_mut();
         let data = if let Some(data) = extensions.get_mut::<EtwLayerData>() {
             data
@@ -478,18 +1742,35 @@ where
             return;
         };
 
-        self.provider.as_ref().span_start(
-            &span,
-            timestamp,
-            &data.activity_id,
-            &data.related_activity_id,
-            &data.fields,
-            map_level(metadata.level()),
-            self.default_keyword,
-            0,
-        );
+        let mut state = data.state(self.id);
+        if !state.enabled {
+            return;
+        }
+
+        if self.span_mode != SpanMode::PerEnter {
+            let now = Instant::now();
+            state.idle += now.duration_since(state.last_transition);
+            state.last_transition = now;
+        }
 
-        data.start_time = timestamp;
+        match self.span_mode {
+            SpanMode::Lifecycle => (),
+            SpanMode::Async if state.started => {
+                self.providers
+                    .route(span.metadata())
+                    .span_resume(&self.span_record(&span, data), timestamp);
+            }
+            SpanMode::PerEnter | SpanMode::Async => {
+                self.providers
+                    .route(span.metadata())
+                    .span_start(&self.span_record(&span, data), timestamp);
+
+                state.start_time = timestamp;
+                state.started = true;
+            }
+        }
+
+        data.set_state(self.id, state);
     }
 
     fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
@@ -502,8 +1783,6 @@ where
             return;
         };
 
-        let metadata = span.metadata();
-
         let mut extensions = span.extensions_mut();
         let data = if let Some(data) = extensions.get_mut::<EtwLayerData>() {
             data
@@ -512,21 +1791,81 @@ where
             return;
         };
 
-        self.provider.as_ref().span_stop(
-            &span,
-            (data.start_time, stop_timestamp),
-            &data.activity_id,
-            &data.related_activity_id,
-            &data.fields,
-            map_level(metadata.level()),
-            self.default_keyword,
-            0,
-        );
+        let mut state = data.state(self.id);
+        if !state.enabled {
+            return;
+        }
+
+        if self.span_mode != SpanMode::PerEnter {
+            let now = Instant::now();
+            state.busy += now.duration_since(state.last_transition);
+            state.last_transition = now;
+        }
+
+        match self.span_mode {
+            SpanMode::Lifecycle => (),
+            SpanMode::Async => {
+                self.providers
+                    .route(span.metadata())
+                    .span_suspend(&self.span_record(&span, data), stop_timestamp);
+            }
+            SpanMode::PerEnter => {
+                self.providers.route(span.metadata()).span_stop(
+                    &self.span_record(&span, data),
+                    (state.start_time, stop_timestamp),
+                );
+            }
+        }
+
+        data.set_state(self.id, state);
     }
 
-    fn on_close(&self, _id: span::Id, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+    fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
         // A span was closed
-        // Good for knowing when to log a summary event?
+        if self.span_mode == SpanMode::PerEnter {
+            return;
+        }
+
+        let stop_timestamp = std::time::SystemTime::now();
+
+        let span = if let Some(span) = ctx.span(&id) {
+            span
+        } else {
+            return;
+        };
+
+        let extensions = span.extensions();
+        let data = if let Some(data) = extensions.get::<EtwLayerData>() {
+            data
+        } else {
+            return;
+        };
+
+        let state = data.state(self.id);
+        if !state.started {
+            // An async span that was never entered
+            return;
+        }
+
+        let idle = state.idle + state.last_transition.elapsed();
+
+        // Other layers may still write the span, so its fields are copied rather than moved.
+        let mut fields = data.fields.to_vec();
+        fields.push(FieldValueIndex {
+            field: "busy_ns",
+            value: Some(ValueTypes::U64(state.busy.as_nanos() as u64)),
+        });
+        fields.push(FieldValueIndex {
+            field: "idle_ns",
+            value: Some(ValueTypes::U64(idle.as_nanos() as u64)),
+        });
+
+        let mut record = self.span_record(&span, data);
+        record.fields = Fields::new(&fields);
+
+        self.providers
+            .route(span.metadata())
+            .span_stop(&record, (state.start_time, stop_timestamp));
     }
 
     fn on_record(
@@ -551,8 +1890,58 @@ where
             return;
         };
 
-        values.record(&mut ValueVisitor {
-            fields: &mut data.fields,
+        if data.owner == self.id {
+            values.record(&mut ValueVisitor::new(&mut data.fields));
+            data.overrides.take_from(&mut data.fields);
+            // The span's parent can't change once it has been created
+            RemoteParent::take_from(&mut data.fields);
+        }
+
+        if !self.span_update_events || !data.state(self.id).enabled {
+            return;
+        }
+
+        let metadata = span.metadata();
+        let level = data
+            .overrides
+            .level
+            .unwrap_or_else(|| map_level(metadata.level()));
+        let keyword = self
+            .settings
+            .keyword_or_override(metadata.target(), data.overrides.keyword);
+
+        let provider = self.providers.route(metadata);
+        if !provider.enabled(level, keyword) {
+            return;
+        }
+
+        // The update event only carries the fields that were just recorded
+        let mut fields = new_fields(metadata);
+        values.record(&mut ValueVisitor::new(&mut fields));
+        HeaderOverrides::default().take_from(&mut fields);
+        RemoteParent::take_from(&mut fields);
+
+        if Fields::new(&fields).is_empty() {
+            return;
+        }
+
+        provider.write_record(&EventRecord {
+            name: metadata.name(),
+            metadata,
+            timestamp: SystemTime::now(),
+            monotonic_timestamp: self.monotonic_timestamp(),
+            span_id: span.id().into_u64(),
+            parent_span_id: span.parent().map_or(0, |parent| parent.id().into_u64()),
+            activity_id: Some(&data.activity_id),
+            related_activity_id: data.related_activity_id.as_ref(),
+            trace_context: Some(&data.trace_context),
+            level,
+            keyword,
+            event_tag: data.overrides.tag.unwrap_or(0),
+            opcode: 0,
+            fields: Fields::new(&fields),
+            metadata_fields: MetadataFields::default(),
+            thread_fields: self.thread_fields,
         });
     }
 }
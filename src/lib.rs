@@ -1,8 +1,28 @@
+mod activity;
+pub mod capture;
+mod config;
+mod error;
+mod keywords;
 mod layer;
 mod native;
+mod otel;
+mod propagation;
+mod reload;
 mod values;
 
+pub use activity::{
+    ActivityIdGenerator, RandomActivityIds, SeededActivityIds, SequentialActivityIds,
+};
+pub use config::{ConfiguredLayerBuilder, EtwConfig, ProviderConfig};
+pub use error::{BuildError, ConfigError};
 pub use layer::*;
+pub use native::{
+    EventRecord, EventWriter, MetadataFields, SpanLink, SpanRecord, ThreadFields, TimestampClock,
+    TraceContext,
+};
+pub use propagation::{current_activity_id, current_traceparent};
+pub use reload::ReloadHandle;
+pub use values::{ErrorValue, FieldAndValue, Fields, FieldsIter, ValueTypes};
 
 #[inline]
 pub(crate) const fn map_level(level: &tracing::Level) -> u8 {
@@ -1,18 +1,11 @@
 use crate::values::*;
-use std::{
-    cell::RefCell,
-    io::{Cursor, Write},
-    mem::MaybeUninit,
-    ops::DerefMut,
-    pin::Pin,
-    sync::Arc,
-    time::SystemTime,
-};
+use crate::BuildError;
+use std::{cell::RefCell, ops::DerefMut, pin::Pin, sync::Arc, time::SystemTime};
 use tracelogging::*;
 use tracelogging_dynamic::EventBuilder;
-use tracing_subscriber::registry::{LookupSpan, SpanRef};
 
-use crate::native::ProviderGroup;
+use super::{code_field_count, span_id_hex, trace_id_hex, ThreadPartB, MAX_STRUCT_FIELDS};
+use crate::native::{EventRecord, ProviderGroup, SpanLink, SpanRecord};
 
 thread_local! {static EBW: std::cell::RefCell<EventBuilder>  = RefCell::new(EventBuilder::new());}
 
@@ -20,23 +13,14 @@ pub(crate) struct CommonSchemaPartCBuilder<'a> {
     pub(crate) eb: &'a mut EventBuilder,
 }
 
-impl<'a> CommonSchemaPartCBuilder<'a> {
-    fn make_visitor(eb: &'a mut EventBuilder) -> VisitorWrapper<CommonSchemaPartCBuilder<'a>> {
-        VisitorWrapper::from(CommonSchemaPartCBuilder { eb })
-    }
-}
-
 impl<T> AddFieldAndValue<T> for CommonSchemaPartCBuilder<'_> {
     fn add_field_value(&mut self, fv: &FieldAndValue) {
         let mut field_name: &'static str = fv.field_name;
 
-        match field_name {
-            "message" => {
-                field_name = "Body";
-                assert!(matches!(fv.value, ValueTypes::v_str(_)));
-            }
-            _ => (),
-        };
+        if field_name == "message" {
+            field_name = "Body";
+            assert!(matches!(fv.value, ValueTypes::Str(_)));
+        }
 
         <&mut EventBuilder as AddFieldAndValue<EventBuilder>>::add_field_value(
             &mut self.eb,
@@ -67,19 +51,13 @@ fn callback_fn(
 }
 
 impl CommonSchemaProvider {
-    #[inline(always)]
-    fn get_provider(self: Pin<&Self>) -> Pin<&tracelogging_dynamic::Provider> {
-        unsafe { self.map_unchecked(|s| &s.provider) }
-    }
-}
-
-impl crate::native::EventWriter for CommonSchemaProvider {
-    fn new<G>(
+    pub(crate) fn new<G>(
         provider_name: &str,
         provider_id: &G,
         provider_group: &ProviderGroup,
-        _default_keyword: u64,
-    ) -> Pin<Arc<Self>>
+        _keywords: &[u64],
+        _timestamp_clock: crate::native::TimestampClock,
+    ) -> (Pin<Arc<Self>>, Result<(), BuildError>)
     where
         for<'a> &'a G: Into<crate::native::GuidWrapper>,
     {
@@ -97,13 +75,24 @@ impl crate::native::EventWriter for CommonSchemaProvider {
                 &provider_id.into().into(),
             ),
         });
-        unsafe {
-            wrapper.as_ref().get_provider().register();
-        }
+        let result = unsafe { wrapper.as_ref().get_provider().register() };
 
-        wrapper
+        let registration = if result == 0 {
+            Ok(())
+        } else {
+            Err(BuildError::RegistrationFailed(result as i32))
+        };
+
+        (wrapper, registration)
     }
 
+    #[inline(always)]
+    fn get_provider(self: Pin<&Self>) -> Pin<&tracelogging_dynamic::Provider> {
+        unsafe { self.map_unchecked(|s| &s.provider) }
+    }
+}
+
+impl crate::native::EventWriter for CommonSchemaProvider {
     #[inline]
     fn enabled(&self, level: u8, keyword: u64) -> bool {
         self.provider
@@ -115,125 +104,116 @@ impl crate::native::EventWriter for CommonSchemaProvider {
         true
     }
 
-    fn span_start<'a, 'b, R>(
-        self: Pin<&Self>,
-        _span: &'b SpanRef<'a, R>,
-        _timestamp: SystemTime,
-        _activity_id: &[u8; 16],
-        _related_activity_id: &[u8; 16],
-        _fields: &'b [crate::values::FieldValueIndex],
-        _level: u8,
-        _keyword: u64,
-        _event_tag: u32,
-    ) where
-        R: LookupSpan<'a>,
-    {
-    }
+    fn span_start(self: Pin<&Self>, _span: &SpanRecord<'_>, _timestamp: SystemTime) {}
 
-    fn span_stop<'a, 'b, R>(
+    fn span_stop(
         self: Pin<&Self>,
-        span: &'b SpanRef<'a, R>,
+        span: &SpanRecord<'_>,
         start_stop_times: (std::time::SystemTime, std::time::SystemTime),
-        _activity_id: &[u8; 16],
-        _related_activity_id: &[u8; 16],
-        fields: &'b [crate::values::FieldValueIndex],
-        level: u8,
-        keyword: u64,
-        event_tag: u32,
-    ) where
-        R: LookupSpan<'a>,
-    {
-        let span_name = span.name();
-
-        let span_id = unsafe {
-            let mut span_id = MaybeUninit::<[u8; 16]>::uninit();
-            let mut cur = Cursor::new((&mut *span_id.as_mut_ptr()).as_mut_slice());
-            write!(&mut cur, "{:16x}", span.id().into_u64()).expect("!write");
-            span_id.assume_init()
-        };
+    ) {
+        let span_id = span_id_hex(&span.trace_context.span_id);
+        let trace_id = trace_id_hex(&span.trace_context.trace_id);
+        let trace_flags = span.trace_context.trace_flags();
 
         EBW.with(|eb| {
             let mut eb = eb.borrow_mut();
 
-            eb.reset(span_name, level.into(), keyword, event_tag);
+            eb.reset(span.name, span.level.into(), span.keyword, span.event_tag);
             eb.opcode(Opcode::Info);
 
             // Promoting values from PartC to PartA extensions is apparently just a draft spec
             // and not necessary / supported by consumers.
             // let exts = json::extract_common_schema_parta_exts(attributes);
 
+            let target = span.metadata_fields.target(span.metadata);
+            let thread = ThreadPartB::new(span.thread_fields);
+
             eb.add_u16("__csver__", 0x0401, OutType::Signed, 0);
-            eb.add_struct("PartA", 2 /* + exts.len() as u8*/, 0);
+            eb.add_struct(
+                "PartA",
+                2 + target.is_some() as u8, /* + exts.len() as u8*/
+                0,
+            );
             {
                 let time: String = chrono::DateTime::to_rfc3339(
                     &chrono::DateTime::<chrono::Utc>::from(start_stop_times.1),
                 );
                 eb.add_str8("time", time, OutType::Utf8, 0);
 
-                eb.add_struct("ext_dt", 2, 0);
+                if let Some(target) = target {
+                    eb.add_str8("name", target, OutType::Utf8, 0);
+                }
+
+                eb.add_struct("ext_dt", 3, 0);
                 {
-                    eb.add_str8("traceId", "", OutType::Utf8, 0); // TODO
-                    eb.add_str8("spanId", &span_id, OutType::Utf8, 0);
+                    eb.add_str8("traceId", trace_id, OutType::Utf8, 0);
+                    eb.add_str8("spanId", span_id, OutType::Utf8, 0);
+                    eb.add_u8("traceFlags", trace_flags, OutType::Default, 0);
                 }
             }
 
-            // if !span_data.links.is_empty() {
-            //     self.add_struct("PartB", 5, 0);
-            //     {
-            //         self.add_str8("_typeName", "SpanLink", OutType::Utf8, 0);
-            //         self.add_str8("fromTraceId", &traceId, OutType::Utf8, 0);
-            //         self.add_str8("fromSpanId", &spanId, OutType::Utf8, 0);
-            //         self.add_str8("toTraceId", "SpanLink", OutType::Utf8, 0);
-            //         self.add_str8("toSpanId", "SpanLink", OutType::Utf8, 0);
-            //     }
-            // }
-
-            let span_parent = span.parent();
-            let partb_field_count = 3 + if span_parent.is_some() { 1 } else { 0 };
+            let partb_field_count =
+                3 + if span.trace_context.parent_span_id.is_some() {
+                    1
+                } else {
+                    0
+                } + code_field_count(span.metadata, span.metadata_fields)
+                    + thread.field_count();
 
             eb.add_struct("PartB", partb_field_count, 0);
             {
                 eb.add_str8("_typeName", "Span", OutType::Utf8, 0);
 
-                if let Some(parent) = span_parent {
-                    let parent_span_id = unsafe {
-                        let mut span_id = MaybeUninit::<[u8; 16]>::uninit();
-                        let mut cur = Cursor::new((&mut *span_id.as_mut_ptr()).as_mut_slice());
-                        write!(&mut cur, "{:16x}", parent.id().into_u64()).expect("!write");
-                        span_id.assume_init()
-                    };
+                if let Some(parent_span_id) = &span.trace_context.parent_span_id {
+                    let parent_span_id = span_id_hex(parent_span_id);
 
-                    eb.add_str8("parentId", &parent_span_id, OutType::Utf8, 0);
+                    eb.add_str8("parentId", parent_span_id, OutType::Utf8, 0);
                 }
 
-                eb.add_str8("name", span_name, OutType::Utf8, 0);
+                eb.add_str8("name", span.name, OutType::Utf8, 0);
 
                 eb.add_str8(
                     "startTime",
-                    &chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(
+                    chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(
                         start_stop_times.0,
                     )),
                     OutType::Utf8,
                     0,
                 );
+
+                if let Some(file) = span.metadata_fields.file(span.metadata) {
+                    eb.add_str8("code.filepath", file, OutType::Utf8, 0);
+                }
+                if let Some(line) = span.metadata_fields.line(span.metadata) {
+                    eb.add_u32("code.lineno", line, OutType::Default, 0);
+                }
+                if let Some(module_path) = span.metadata_fields.module_path(span.metadata) {
+                    eb.add_str8("code.namespace", module_path, OutType::Utf8, 0);
+                }
+
+                if let Some(thread_id) = thread.thread_id {
+                    eb.add_u64("thread.id", thread_id, OutType::Default, 0);
+                }
+                if let Some(thread_name) = &thread.thread_name {
+                    eb.add_str8("thread.name", thread_name.as_ref(), OutType::Utf8, 0);
+                }
+                if let Some(process_id) = thread.process_id {
+                    eb.add_u32("process.pid", process_id, OutType::Default, 0);
+                }
             }
 
-            let partc_field_count = span.fields().len() as u8;
+            let partc_field_count = span.fields.len().min(MAX_STRUCT_FIELDS);
 
-            eb.add_struct("PartC", partc_field_count, 0);
-            {
-                let mut pfv = CommonSchemaPartCBuilder { eb: eb.deref_mut() };
-
-                for f in fields {
-                    <CommonSchemaPartCBuilder<'_> as AddFieldAndValue<
-                        CommonSchemaPartCBuilder<'_>,
-                    >>::add_field_value(
-                        &mut pfv,
-                        &FieldAndValue {
-                            field_name: f.field,
-                            value: &f.value,
-                        },
-                    );
+            if partc_field_count > 0 {
+                eb.add_struct("PartC", partc_field_count as u8, 0);
+                {
+                    let mut pfv = CommonSchemaPartCBuilder { eb: eb.deref_mut() };
+
+                    for f in span.fields.iter().take(partc_field_count) {
+                        <CommonSchemaPartCBuilder<'_> as AddFieldAndValue<
+                            CommonSchemaPartCBuilder<'_>,
+                        >>::add_field_value(&mut pfv, &f);
+                    }
                 }
             }
 
@@ -241,74 +221,176 @@ impl crate::native::EventWriter for CommonSchemaProvider {
         });
     }
 
-    fn write_record(
-        self: Pin<&Self>,
-        timestamp: SystemTime,
-        current_span: u64,
-        _parent_span: u64,
-        event_name: &str,
-        level: u8,
-        keyword: u64,
-        event: &tracing::Event<'_>,
-    ) {
+    fn span_link(self: Pin<&Self>, span: &SpanRecord<'_>, link: &SpanLink, timestamp: SystemTime) {
+        let span_id = span_id_hex(&span.trace_context.span_id);
+        let trace_id = trace_id_hex(&span.trace_context.trace_id);
+        let trace_flags = span.trace_context.trace_flags();
+        let to_span_id = span_id_hex(&link.trace_context.span_id);
+        let to_trace_id = trace_id_hex(&link.trace_context.trace_id);
+
         EBW.with(|eb| {
             let mut eb = eb.borrow_mut();
 
-            eb.reset(event_name, level.into(), keyword, 0);
+            eb.reset(span.name, span.level.into(), span.keyword, span.event_tag);
             eb.opcode(Opcode::Info);
 
+            eb.add_u16("__csver__", 0x0401, OutType::Signed, 0);
+            eb.add_struct("PartA", 2, 0);
+            {
+                let time: String =
+                    chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(timestamp));
+                eb.add_str8("time", time, OutType::Utf8, 0);
+
+                eb.add_struct("ext_dt", 3, 0);
+                {
+                    eb.add_str8("traceId", trace_id, OutType::Utf8, 0);
+                    eb.add_str8("spanId", span_id, OutType::Utf8, 0);
+                    eb.add_u8("traceFlags", trace_flags, OutType::Default, 0);
+                }
+            }
+
+            eb.add_struct("PartB", 5, 0);
+            {
+                eb.add_str8("_typeName", "SpanLink", OutType::Utf8, 0);
+                eb.add_str8("fromTraceId", trace_id, OutType::Utf8, 0);
+                eb.add_str8("fromSpanId", span_id, OutType::Utf8, 0);
+                eb.add_str8("toTraceId", to_trace_id, OutType::Utf8, 0);
+                eb.add_str8("toSpanId", to_span_id, OutType::Utf8, 0);
+            }
+
+            let _ = eb.write(&self.get_provider(), None, None);
+        });
+    }
+
+    fn write_record(self: Pin<&Self>, event: &EventRecord<'_>) {
+        EBW.with(|eb| {
+            let mut eb = eb.borrow_mut();
+
+            eb.reset(
+                event.name,
+                event.level.into(),
+                event.keyword,
+                event.event_tag,
+            );
+            eb.opcode(Opcode::from_int(event.opcode));
+
             // Promoting values from PartC to PartA extensions is apparently just a draft spec
             // and not necessary / supported by consumers.
             // let exts = json::extract_common_schema_parta_exts(attributes);
 
+            let target = event.metadata_fields.target(event.metadata);
+            let thread = ThreadPartB::new(event.thread_fields);
+
             eb.add_u16("__csver__", 0x0401, OutType::Signed, 0);
             eb.add_struct(
                 "PartA",
-                1 + if current_span != 0 { 1 } else { 0 }, /* + exts.len() as u8*/
+                1 + if event.trace_context.is_some() { 1 } else { 0 }
+                    + target.is_some() as u8, /* + exts.len() as u8*/
                 0,
             );
             {
-                let time: String =
-                    chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(timestamp));
+                let time: String = chrono::DateTime::to_rfc3339(
+                    &chrono::DateTime::<chrono::Utc>::from(event.timestamp),
+                );
                 eb.add_str8("time", time, OutType::Utf8, 0);
 
-                if current_span != 0 {
-                    eb.add_struct("ext_dt", 2, 0);
+                if let Some(target) = target {
+                    eb.add_str8("name", target, OutType::Utf8, 0);
+                }
+
+                if let Some(trace_context) = event.trace_context {
+                    eb.add_struct("ext_dt", 3, 0);
                     {
-                        let span_id = unsafe {
-                            let mut span_id = MaybeUninit::<[u8; 16]>::uninit();
-                            let mut cur = Cursor::new((&mut *span_id.as_mut_ptr()).as_mut_slice());
-                            write!(&mut cur, "{:16x}", current_span).expect("!write");
-                            span_id.assume_init()
-                        };
-
-                        eb.add_str8("traceId", "", OutType::Utf8, 0); // TODO
-                        eb.add_str8("spanId", &span_id, OutType::Utf8, 0);
+                        let trace_id = trace_id_hex(&trace_context.trace_id);
+                        let span_id = span_id_hex(&trace_context.span_id);
+                        let trace_flags = trace_context.trace_flags();
+
+                        eb.add_str8("traceId", trace_id, OutType::Utf8, 0);
+                        eb.add_str8("spanId", span_id, OutType::Utf8, 0);
+                        eb.add_u8("traceFlags", trace_flags, OutType::Default, 0);
                     }
                 }
             }
 
-            eb.add_struct("PartB", 3, 0);
+            // The first error recorded on the event turns it into an Exception,
+            // and is written in PartB instead of PartC.
+            let exception = event.fields.iter().find_map(|f| match f.value {
+                ValueTypes::Error(e) => Some((f.field_name, e.as_ref())),
+                _ => None,
+            });
+
+            eb.add_struct(
+                "PartB",
+                (if exception.is_some() { 5 } else { 3 })
+                    + code_field_count(event.metadata, event.metadata_fields)
+                    + thread.field_count(),
+                0,
+            );
             {
-                eb.add_str8("_typeName", "Log", OutType::Utf8, 0);
-                eb.add_str8("name", event_name, OutType::Utf8, 0);
+                eb.add_str8(
+                    "_typeName",
+                    if exception.is_some() {
+                        "Exception"
+                    } else {
+                        "Log"
+                    },
+                    OutType::Utf8,
+                    0,
+                );
+                eb.add_str8("name", event.name, OutType::Utf8, 0);
 
                 eb.add_str8(
                     "eventTime",
-                    &chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(
-                        timestamp,
+                    chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(
+                        event.timestamp,
                     )),
                     OutType::Utf8,
                     0,
                 );
+
+                if let Some((_, error)) = exception {
+                    eb.add_str8("message", &error.message, OutType::Utf8, 0);
+                    eb.add_str8_sequence("causes", &error.sources, OutType::Utf8, 0);
+                }
+
+                if let Some(file) = event.metadata_fields.file(event.metadata) {
+                    eb.add_str8("code.filepath", file, OutType::Utf8, 0);
+                }
+                if let Some(line) = event.metadata_fields.line(event.metadata) {
+                    eb.add_u32("code.lineno", line, OutType::Default, 0);
+                }
+                if let Some(module_path) = event.metadata_fields.module_path(event.metadata) {
+                    eb.add_str8("code.namespace", module_path, OutType::Utf8, 0);
+                }
+
+                if let Some(thread_id) = thread.thread_id {
+                    eb.add_u64("thread.id", thread_id, OutType::Default, 0);
+                }
+                if let Some(thread_name) = &thread.thread_name {
+                    eb.add_str8("thread.name", thread_name.as_ref(), OutType::Utf8, 0);
+                }
+                if let Some(process_id) = thread.process_id {
+                    eb.add_u32("process.pid", process_id, OutType::Default, 0);
+                }
             }
 
-            let partc_field_count = event.fields().count() as u8;
+            let partc_fields = event
+                .fields
+                .iter()
+                .filter(|f| Some(f.field_name) != exception.map(|(name, _)| name));
+            let partc_field_count = partc_fields.clone().count().min(MAX_STRUCT_FIELDS);
 
-            eb.add_struct("PartC", partc_field_count, 0);
-            {
-                let mut visitor = CommonSchemaPartCBuilder::make_visitor(eb.deref_mut());
-                event.record(&mut visitor);
+            if partc_field_count > 0 {
+                eb.add_struct("PartC", partc_field_count as u8, 0);
+                {
+                    let mut pfv = CommonSchemaPartCBuilder { eb: eb.deref_mut() };
+
+                    for f in partc_fields.take(partc_field_count) {
+                        <CommonSchemaPartCBuilder<'_> as AddFieldAndValue<
+                            CommonSchemaPartCBuilder<'_>,
+                        >>::add_field_value(&mut pfv, &f);
+                    }
+                }
             }
 
             let _ = eb.write(&self.get_provider(), None, None);
@@ -22,4 +22,96 @@ pub use noop::Provider;
 
 impl crate::native::EventMode for Provider {
     type Provider = Provider;
+    type Backend = ();
+
+    fn new_provider<G>(
+        _backend: &(),
+        provider_name: &str,
+        provider_id: &G,
+        provider_group: &crate::native::ProviderGroup,
+        keywords: &[u64],
+        timestamp_clock: crate::native::TimestampClock,
+    ) -> (
+        std::pin::Pin<std::sync::Arc<Self::Provider>>,
+        Result<(), crate::BuildError>,
+    )
+    where
+        for<'a> &'a G: Into<crate::native::GuidWrapper>,
+    {
+        Provider::new(
+            provider_name,
+            provider_id,
+            provider_group,
+            keywords,
+            timestamp_clock,
+        )
+    }
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+/// The most fields a struct can hold in either encoding. PartC fields beyond
+/// this are dropped, keeping the first ones in declaration order.
+pub(crate) const MAX_STRUCT_FIELDS: usize = 127;
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+/// The number of PartB `code.*` fields written for the callsite.
+pub(crate) fn code_field_count(
+    metadata: &tracing::Metadata<'static>,
+    metadata_fields: crate::native::MetadataFields,
+) -> u8 {
+    metadata_fields.file(metadata).is_some() as u8
+        + metadata_fields.line(metadata).is_some() as u8
+        + metadata_fields.module_path(metadata).is_some() as u8
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+/// The thread and process details written as PartB fields, read once per event.
+pub(crate) struct ThreadPartB {
+    pub(crate) thread_id: Option<u64>,
+    pub(crate) thread_name: Option<std::sync::Arc<str>>,
+    pub(crate) process_id: Option<u32>,
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+impl ThreadPartB {
+    pub(crate) fn new(thread_fields: crate::native::ThreadFields) -> Self {
+        ThreadPartB {
+            thread_id: thread_fields.thread_id(),
+            thread_name: thread_fields.thread_name(),
+            process_id: thread_fields.process_id(),
+        }
+    }
+
+    pub(crate) fn field_count(&self) -> u8 {
+        self.thread_id.is_some() as u8
+            + self.thread_name.is_some() as u8
+            + self.process_id.is_some() as u8
+    }
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+fn write_hex(bytes: &[u8], hex: &mut [u8]) {
+    for (i, b) in bytes.iter().enumerate() {
+        hex[i * 2] = HEX_DIGITS[(b >> 4) as usize];
+        hex[i * 2 + 1] = HEX_DIGITS[(b & 0xf) as usize];
+    }
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+/// Format a trace ID as the 32 lowercase hex characters used by W3C trace context.
+pub(crate) fn trace_id_hex(trace_id: &[u8; 16]) -> [u8; 32] {
+    let mut hex = [0u8; 32];
+    write_hex(trace_id, &mut hex);
+    hex
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+/// Format a span ID as the 16 lowercase hex characters used by W3C trace context.
+pub(crate) fn span_id_hex(span_id: &[u8; 8]) -> [u8; 16] {
+    let mut hex = [0u8; 16];
+    write_hex(span_id, &mut hex);
+    hex
 }
@@ -1,18 +1,10 @@
-use crate::{map_level, values::*};
+use crate::{map_level, values::*, BuildError};
 use eventheader::*;
 use eventheader_dynamic::EventBuilder;
-use std::{
-    cell::RefCell,
-    io::{Cursor, Write},
-    mem::MaybeUninit,
-    ops::DerefMut,
-    pin::Pin,
-    sync::Arc,
-    time::SystemTime,
-};
-use tracing_subscriber::registry::{LookupSpan, SpanRef};
-
-use crate::native::ProviderGroup;
+use std::{cell::RefCell, ops::DerefMut, pin::Pin, sync::Arc, time::SystemTime};
+
+use super::{code_field_count, span_id_hex, trace_id_hex, ThreadPartB, MAX_STRUCT_FIELDS};
+use crate::native::{EventRecord, ProviderGroup, SpanLink, SpanRecord};
 
 thread_local! {static EBW: std::cell::RefCell<EventBuilder>  = RefCell::new(EventBuilder::new());}
 
@@ -20,23 +12,14 @@ pub(crate) struct CommonSchemaPartCBuilder<'a> {
     pub(crate) eb: &'a mut EventBuilder,
 }
 
-impl<'a> CommonSchemaPartCBuilder<'a> {
-    fn make_visitor(eb: &'a mut EventBuilder) -> VisitorWrapper<CommonSchemaPartCBuilder<'a>> {
-        VisitorWrapper::from(CommonSchemaPartCBuilder { eb })
-    }
-}
-
 impl<T> AddFieldAndValue<T> for CommonSchemaPartCBuilder<'_> {
     fn add_field_value(&mut self, fv: &FieldAndValue) {
         let mut field_name: &'static str = fv.field_name;
 
-        match field_name {
-            "message" => {
-                field_name = "Body";
-                assert!(matches!(fv.value, ValueTypes::v_str(_)));
-            }
-            _ => (),
-        };
+        if field_name == "message" {
+            field_name = "Body";
+            assert!(matches!(fv.value, ValueTypes::Str(_)));
+        }
 
         <&mut EventBuilder as AddFieldAndValue<EventBuilder>>::add_field_value(
             &mut self.eb,
@@ -54,6 +37,55 @@ pub struct CommonSchemaProvider {
 }
 
 impl CommonSchemaProvider {
+    pub(crate) fn new<G>(
+        provider_name: &str,
+        _: &G,
+        provider_group: &ProviderGroup,
+        keywords: &[u64],
+        _timestamp_clock: crate::native::TimestampClock,
+    ) -> (Pin<Arc<Self>>, Result<(), BuildError>)
+    where
+        for<'a> &'a G: Into<crate::native::GuidWrapper>,
+    {
+        let mut options = eventheader_dynamic::Provider::new_options();
+        if let ProviderGroup::Linux(ref name) = provider_group {
+            options = *options.group_name(name);
+        }
+        let mut provider = eventheader_dynamic::Provider::new(provider_name, &options);
+
+        let mut errno = 0;
+        for keyword in keywords {
+            for level in [
+                tracing::Level::ERROR,
+                tracing::Level::WARN,
+                tracing::Level::INFO,
+                tracing::Level::DEBUG,
+                tracing::Level::TRACE,
+            ] {
+                let es = provider.register_set(
+                    eventheader_dynamic::Level::from_int(map_level(&level)),
+                    *keyword,
+                );
+                if errno == 0 {
+                    errno = es.errno();
+                }
+            }
+        }
+
+        let registration = if errno == 0 {
+            Ok(())
+        } else {
+            Err(BuildError::RegistrationFailed(errno))
+        };
+
+        (
+            Arc::pin(Self {
+                provider: std::sync::RwLock::new(provider),
+            }),
+            registration,
+        )
+    }
+
     fn find_set(
         self: Pin<&Self>,
         level: eventheader_dynamic::Level,
@@ -79,47 +111,6 @@ impl CommonSchemaProvider {
 }
 
 impl crate::native::EventWriter for CommonSchemaProvider {
-    fn new<G>(
-        provider_name: &str,
-        _: &G,
-        provider_group: &ProviderGroup,
-        default_keyword: u64,
-    ) -> Pin<Arc<Self>>
-    where
-        for<'a> &'a G: Into<crate::native::GuidWrapper>,
-    {
-        let mut options = eventheader_dynamic::Provider::new_options();
-        if let ProviderGroup::Linux(ref name) = provider_group {
-            options = *options.group_name(&name);
-        }
-        let mut provider = eventheader_dynamic::Provider::new(provider_name, &options);
-
-        provider.register_set(
-            eventheader_dynamic::Level::from_int(map_level(&tracing::Level::ERROR)),
-            default_keyword,
-        );
-        provider.register_set(
-            eventheader_dynamic::Level::from_int(map_level(&tracing::Level::WARN)),
-            default_keyword,
-        );
-        provider.register_set(
-            eventheader_dynamic::Level::from_int(map_level(&tracing::Level::INFO)),
-            default_keyword,
-        );
-        provider.register_set(
-            eventheader_dynamic::Level::from_int(map_level(&tracing::Level::DEBUG)),
-            default_keyword,
-        );
-        provider.register_set(
-            eventheader_dynamic::Level::from_int(map_level(&tracing::Level::TRACE)),
-            default_keyword,
-        );
-
-        Arc::pin(Self {
-            provider: std::sync::RwLock::new(provider),
-        })
-    }
-
     #[inline]
     fn enabled(&self, level: u8, keyword: u64) -> bool {
         let es = self
@@ -127,7 +118,18 @@ impl crate::native::EventWriter for CommonSchemaProvider {
             .read()
             .unwrap()
             .find_set(eventheader_dynamic::Level::from_int(level), keyword);
-        return if let Some(s) = es { s.enabled() } else { false };
+        if let Some(s) = es {
+            s.enabled()
+        } else {
+            // Keywords set through a ReloadHandle aren't known when the provider is built.
+            // Register the tracepoint now so a session can enable it for later events.
+            // The layer only passes configured keywords and levels, so this set is bounded.
+            self.provider
+                .write()
+                .unwrap()
+                .register_set(eventheader_dynamic::Level::from_int(level), keyword)
+                .enabled()
+        }
     }
 
     #[inline(always)]
@@ -135,131 +137,122 @@ impl crate::native::EventWriter for CommonSchemaProvider {
         false
     }
 
-    fn span_start<'a, 'b, R>(
-        self: Pin<&Self>,
-        _span: &'b SpanRef<'a, R>,
-        _timestamp: SystemTime,
-        _activity_id: &[u8; 16],
-        _related_activity_id: &[u8; 16],
-        _fields: &'b [crate::values::FieldValueIndex],
-        _level: u8,
-        _keyword: u64,
-        _event_tag: u32,
-    ) where
-        R: LookupSpan<'a>,
-    {
-    }
+    fn span_start(self: Pin<&Self>, _span: &SpanRecord<'_>, _timestamp: SystemTime) {}
 
-    fn span_stop<'a, 'b, R>(
+    fn span_stop(
         self: Pin<&Self>,
-        span: &'b SpanRef<'a, R>,
+        span: &SpanRecord<'_>,
         start_stop_times: (std::time::SystemTime, std::time::SystemTime),
-        _activity_id: &[u8; 16],
-        _related_activity_id: &[u8; 16],
-        fields: &'b [crate::values::FieldValueIndex],
-        level: u8,
-        keyword: u64,
-        event_tag: u32,
-    ) where
-        R: LookupSpan<'a>,
-    {
-        let span_name = span.name();
-
-        let span_id = unsafe {
-            let mut span_id = MaybeUninit::<[u8; 16]>::uninit();
-            let mut cur = Cursor::new((&mut *span_id.as_mut_ptr()).as_mut_slice());
-            write!(&mut cur, "{:16x}", span.id().into_u64()).expect("!write");
-            span_id.assume_init()
-        };
+    ) {
+        let span_id = span_id_hex(&span.trace_context.span_id);
+        let trace_id = trace_id_hex(&span.trace_context.trace_id);
+        let trace_flags = span.trace_context.trace_flags();
 
-        let es = if let Some(es) = self.find_set(level.into(), keyword) {
+        let es = if let Some(es) = self.find_set(span.level.into(), span.keyword) {
             es
         } else {
-            self.register_set(level.into(), keyword)
+            self.register_set(span.level.into(), span.keyword)
         };
 
         EBW.with(|eb| {
             let mut eb = eb.borrow_mut();
 
-            eb.reset(span_name, event_tag as u16);
+            eb.reset(span.name, span.event_tag as u16);
             eb.opcode(Opcode::Info);
 
             // Promoting values from PartC to PartA extensions is apparently just a draft spec
             // and not necessary / supported by consumers.
             // let exts = json::extract_common_schema_parta_exts(attributes);
 
+            let target = span.metadata_fields.target(span.metadata);
+            let thread = ThreadPartB::new(span.thread_fields);
+
             eb.add_value("__csver__", 0x0401, FieldFormat::SignedInt, 0);
-            eb.add_struct("PartA", 2 /* + exts.len() as u8*/, 0);
+            eb.add_struct(
+                "PartA",
+                2 + target.is_some() as u8, /* + exts.len() as u8*/
+                0,
+            );
             {
                 let time: String = chrono::DateTime::to_rfc3339(
                     &chrono::DateTime::<chrono::Utc>::from(start_stop_times.1),
                 );
                 eb.add_str("time", time, FieldFormat::Default, 0);
 
-                eb.add_struct("ext_dt", 2, 0);
+                if let Some(target) = target {
+                    eb.add_str("name", target, FieldFormat::Default, 0);
+                }
+
+                eb.add_struct("ext_dt", 3, 0);
                 {
-                    eb.add_str("traceId", "", FieldFormat::Default, 0); // TODO
-                    eb.add_str("spanId", &span_id, FieldFormat::Default, 0);
+                    eb.add_str("traceId", trace_id, FieldFormat::Default, 0);
+                    eb.add_str("spanId", span_id, FieldFormat::Default, 0);
+                    eb.add_value("traceFlags", trace_flags, FieldFormat::Default, 0);
                 }
             }
 
-            // if !span_data.links.is_empty() {
-            //     self.add_struct("PartB", 5, 0);
-            //     {
-            //         self.add_str8("_typeName", "SpanLink", FieldFormat::Default, 0);
-            //         self.add_str8("fromTraceId", &traceId, FieldFormat::Default, 0);
-            //         self.add_str8("fromSpanId", &spanId, FieldFormat::Default, 0);
-            //         self.add_str8("toTraceId", "SpanLink", FieldFormat::Default, 0);
-            //         self.add_str8("toSpanId", "SpanLink", FieldFormat::Default, 0);
-            //     }
-            // }
-
-            let span_parent = span.parent();
-            let partb_field_count = 3 + if span_parent.is_some() { 1 } else { 0 };
+            let partb_field_count =
+                3 + if span.trace_context.parent_span_id.is_some() {
+                    1
+                } else {
+                    0
+                } + code_field_count(span.metadata, span.metadata_fields)
+                    + thread.field_count();
 
             eb.add_struct("PartB", partb_field_count, 0);
             {
                 eb.add_str("_typeName", "Span", FieldFormat::Default, 0);
 
-                if let Some(parent) = span_parent {
-                    let parent_span_id = unsafe {
-                        let mut span_id = MaybeUninit::<[u8; 16]>::uninit();
-                        let mut cur = Cursor::new((&mut *span_id.as_mut_ptr()).as_mut_slice());
-                        write!(&mut cur, "{:16x}", parent.id().into_u64()).expect("!write");
-                        span_id.assume_init()
-                    };
+                if let Some(parent_span_id) = &span.trace_context.parent_span_id {
+                    let parent_span_id = span_id_hex(parent_span_id);
 
-                    eb.add_str("parentId", &parent_span_id, FieldFormat::Default, 0);
+                    eb.add_str("parentId", parent_span_id, FieldFormat::Default, 0);
                 }
 
-                eb.add_str("name", span_name, FieldFormat::Default, 0);
+                eb.add_str("name", span.name, FieldFormat::Default, 0);
 
                 eb.add_str(
                     "startTime",
-                    &chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(
+                    chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(
                         start_stop_times.0,
                     )),
                     FieldFormat::Default,
                     0,
                 );
+
+                if let Some(file) = span.metadata_fields.file(span.metadata) {
+                    eb.add_str("code.filepath", file, FieldFormat::Default, 0);
+                }
+                if let Some(line) = span.metadata_fields.line(span.metadata) {
+                    eb.add_value("code.lineno", line, FieldFormat::Default, 0);
+                }
+                if let Some(module_path) = span.metadata_fields.module_path(span.metadata) {
+                    eb.add_str("code.namespace", module_path, FieldFormat::Default, 0);
+                }
+
+                if let Some(thread_id) = thread.thread_id {
+                    eb.add_value("thread.id", thread_id, FieldFormat::Default, 0);
+                }
+                if let Some(thread_name) = &thread.thread_name {
+                    eb.add_str("thread.name", thread_name.as_ref(), FieldFormat::Default, 0);
+                }
+                if let Some(process_id) = thread.process_id {
+                    eb.add_value("process.pid", process_id, FieldFormat::Default, 0);
+                }
             }
 
-            let partc_field_count = span.fields().len() as u8;
+            let partc_field_count = span.fields.len().min(MAX_STRUCT_FIELDS);
 
-            eb.add_struct("PartC", partc_field_count, 0);
-            {
-                let mut pfv = CommonSchemaPartCBuilder { eb: eb.deref_mut() };
-
-                for f in fields {
-                    <CommonSchemaPartCBuilder<'_> as AddFieldAndValue<
-                        CommonSchemaPartCBuilder<'_>,
-                    >>::add_field_value(
-                        &mut pfv,
-                        &FieldAndValue {
-                            field_name: f.field,
-                            value: &f.value,
-                        },
-                    );
+            if partc_field_count > 0 {
+                eb.add_struct("PartC", partc_field_count as u8, 0);
+                {
+                    let mut pfv = CommonSchemaPartCBuilder { eb: eb.deref_mut() };
+
+                    for f in span.fields.iter().take(partc_field_count) {
+                        <CommonSchemaPartCBuilder<'_> as AddFieldAndValue<
+                            CommonSchemaPartCBuilder<'_>,
+                        >>::add_field_value(&mut pfv, &f);
+                    }
                 }
             }
 
@@ -267,80 +260,183 @@ impl crate::native::EventWriter for CommonSchemaProvider {
         });
     }
 
-    fn write_record(
-        self: Pin<&Self>,
-        timestamp: SystemTime,
-        current_span: u64,
-        _parent_span: u64,
-        event_name: &str,
-        level: u8,
-        keyword: u64,
-        event: &tracing::Event<'_>,
-    ) {
-        let es = if let Some(es) = self.find_set(level.into(), keyword) {
+    fn span_link(self: Pin<&Self>, span: &SpanRecord<'_>, link: &SpanLink, timestamp: SystemTime) {
+        let span_id = span_id_hex(&span.trace_context.span_id);
+        let trace_id = trace_id_hex(&span.trace_context.trace_id);
+        let trace_flags = span.trace_context.trace_flags();
+        let to_span_id = span_id_hex(&link.trace_context.span_id);
+        let to_trace_id = trace_id_hex(&link.trace_context.trace_id);
+
+        let es = if let Some(es) = self.find_set(span.level.into(), span.keyword) {
             es
         } else {
-            self.register_set(level.into(), keyword)
+            self.register_set(span.level.into(), span.keyword)
         };
 
         EBW.with(|eb| {
             let mut eb = eb.borrow_mut();
 
-            eb.reset(event_name, 0);
+            eb.reset(span.name, span.event_tag as u16);
             eb.opcode(Opcode::Info);
 
+            eb.add_value("__csver__", 0x0401, FieldFormat::SignedInt, 0);
+            eb.add_struct("PartA", 2, 0);
+            {
+                let time: String =
+                    chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(timestamp));
+                eb.add_str("time", time, FieldFormat::Default, 0);
+
+                eb.add_struct("ext_dt", 3, 0);
+                {
+                    eb.add_str("traceId", trace_id, FieldFormat::Default, 0);
+                    eb.add_str("spanId", span_id, FieldFormat::Default, 0);
+                    eb.add_value("traceFlags", trace_flags, FieldFormat::Default, 0);
+                }
+            }
+
+            eb.add_struct("PartB", 5, 0);
+            {
+                eb.add_str("_typeName", "SpanLink", FieldFormat::Default, 0);
+                eb.add_str("fromTraceId", trace_id, FieldFormat::Default, 0);
+                eb.add_str("fromSpanId", span_id, FieldFormat::Default, 0);
+                eb.add_str("toTraceId", to_trace_id, FieldFormat::Default, 0);
+                eb.add_str("toSpanId", to_span_id, FieldFormat::Default, 0);
+            }
+
+            let _ = eb.write(&es, None, None);
+        });
+    }
+
+    fn write_record(self: Pin<&Self>, event: &EventRecord<'_>) {
+        let es = if let Some(es) = self.find_set(event.level.into(), event.keyword) {
+            es
+        } else {
+            self.register_set(event.level.into(), event.keyword)
+        };
+
+        EBW.with(|eb| {
+            let mut eb = eb.borrow_mut();
+
+            eb.reset(event.name, event.event_tag as u16);
+            eb.opcode(Opcode::from_int(event.opcode));
+
             // Promoting values from PartC to PartA extensions is apparently just a draft spec
             // and not necessary / supported by consumers.
             // let exts = json::extract_common_schema_parta_exts(attributes);
 
+            let target = event.metadata_fields.target(event.metadata);
+            let thread = ThreadPartB::new(event.thread_fields);
+
             eb.add_value("__csver__", 0x0401, FieldFormat::SignedInt, 0);
             eb.add_struct(
                 "PartA",
-                1 + if current_span != 0 { 1 } else { 0 }, /* + exts.len() as u8*/
+                1 + if event.trace_context.is_some() { 1 } else { 0 }
+                    + target.is_some() as u8, /* + exts.len() as u8*/
                 0,
             );
             {
-                let time: String =
-                    chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(timestamp));
+                let time: String = chrono::DateTime::to_rfc3339(
+                    &chrono::DateTime::<chrono::Utc>::from(event.timestamp),
+                );
                 eb.add_str("time", time, FieldFormat::Default, 0);
 
-                if current_span != 0 {
-                    eb.add_struct("ext_dt", 2, 0);
+                if let Some(target) = target {
+                    eb.add_str("name", target, FieldFormat::Default, 0);
+                }
+
+                if let Some(trace_context) = event.trace_context {
+                    eb.add_struct("ext_dt", 3, 0);
                     {
-                        let span_id = unsafe {
-                            let mut span_id = MaybeUninit::<[u8; 16]>::uninit();
-                            let mut cur = Cursor::new((&mut *span_id.as_mut_ptr()).as_mut_slice());
-                            write!(&mut cur, "{:16x}", current_span).expect("!write");
-                            span_id.assume_init()
-                        };
-
-                        eb.add_str("traceId", "", FieldFormat::Default, 0); // TODO
-                        eb.add_str("spanId", &span_id, FieldFormat::Default, 0);
+                        let trace_id = trace_id_hex(&trace_context.trace_id);
+                        let span_id = span_id_hex(&trace_context.span_id);
+                        let trace_flags = trace_context.trace_flags();
+
+                        eb.add_str("traceId", trace_id, FieldFormat::Default, 0);
+                        eb.add_str("spanId", span_id, FieldFormat::Default, 0);
+                        eb.add_value("traceFlags", trace_flags, FieldFormat::Default, 0);
                     }
                 }
             }
 
-            eb.add_struct("PartB", 3, 0);
+            // The first error recorded on the event turns it into an Exception,
+            // and is written in PartB instead of PartC.
+            let exception = event.fields.iter().find_map(|f| match f.value {
+                ValueTypes::Error(e) => Some((f.field_name, e.as_ref())),
+                _ => None,
+            });
+
+            eb.add_struct(
+                "PartB",
+                (if exception.is_some() { 5 } else { 3 })
+                    + code_field_count(event.metadata, event.metadata_fields)
+                    + thread.field_count(),
+                0,
+            );
             {
-                eb.add_str("_typeName", "Log", FieldFormat::Default, 0);
-                eb.add_str("name", event_name, FieldFormat::Default, 0);
+                eb.add_str(
+                    "_typeName",
+                    if exception.is_some() {
+                        "Exception"
+                    } else {
+                        "Log"
+                    },
+                    FieldFormat::Default,
+                    0,
+                );
+                eb.add_str("name", event.name, FieldFormat::Default, 0);
 
                 eb.add_str(
                     "eventTime",
-                    &chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(
-                        timestamp,
+                    chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(
+                        event.timestamp,
                     )),
                     FieldFormat::Default,
                     0,
                 );
+
+                if let Some((_, error)) = exception {
+                    eb.add_str("message", &error.message, FieldFormat::Default, 0);
+                    eb.add_str_sequence("causes", &error.sources, FieldFormat::Default, 0);
+                }
+
+                if let Some(file) = event.metadata_fields.file(event.metadata) {
+                    eb.add_str("code.filepath", file, FieldFormat::Default, 0);
+                }
+                if let Some(line) = event.metadata_fields.line(event.metadata) {
+                    eb.add_value("code.lineno", line, FieldFormat::Default, 0);
+                }
+                if let Some(module_path) = event.metadata_fields.module_path(event.metadata) {
+                    eb.add_str("code.namespace", module_path, FieldFormat::Default, 0);
+                }
+
+                if let Some(thread_id) = thread.thread_id {
+                    eb.add_value("thread.id", thread_id, FieldFormat::Default, 0);
+                }
+                if let Some(thread_name) = &thread.thread_name {
+                    eb.add_str("thread.name", thread_name.as_ref(), FieldFormat::Default, 0);
+                }
+                if let Some(process_id) = thread.process_id {
+                    eb.add_value("process.pid", process_id, FieldFormat::Default, 0);
+                }
             }
 
-            let partc_field_count = event.fields().count() as u8;
+            let partc_fields = event
+                .fields
+                .iter()
+                .filter(|f| Some(f.field_name) != exception.map(|(name, _)| name));
+            let partc_field_count = partc_fields.clone().count().min(MAX_STRUCT_FIELDS);
 
-            eb.add_struct("PartC", partc_field_count, 0);
-            {
-                let mut visitor = CommonSchemaPartCBuilder::make_visitor(eb.deref_mut());
-                event.record(&mut visitor);
+            if partc_field_count > 0 {
+                eb.add_struct("PartC", partc_field_count as u8, 0);
+                {
+                    let mut pfv = CommonSchemaPartCBuilder { eb: eb.deref_mut() };
+
+                    for f in partc_fields.take(partc_field_count) {
+                        <CommonSchemaPartCBuilder<'_> as AddFieldAndValue<
+                            CommonSchemaPartCBuilder<'_>,
+                        >>::add_field_value(&mut pfv, &f);
+                    }
+                }
             }
 
             let _ = eb.write(&es, None, None);
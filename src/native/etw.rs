@@ -1,11 +1,11 @@
-use crate::{values::*, GLOBAL_ACTIVITY_SEED};
+use crate::values::*;
+use crate::BuildError;
 use chrono::{Datelike, Timelike};
 use std::{cell::RefCell, ops::DerefMut, pin::Pin, sync::Arc, time::SystemTime};
 use tracelogging::*;
 use tracelogging_dynamic::EventBuilder;
-use tracing_subscriber::registry::{LookupSpan, SpanRef};
 
-use super::ProviderGroup;
+use super::{EventRecord, MetadataFields, ProviderGroup, SpanLink, SpanRecord, ThreadFields};
 
 thread_local! {static EBW: std::cell::RefCell<EventBuilder>  = RefCell::new(EventBuilder::new());}
 
@@ -35,36 +35,71 @@ impl From<std::time::SystemTime> for Win32SystemTime {
 impl<T> AddFieldAndValue<T> for &'_ mut tracelogging_dynamic::EventBuilder {
     fn add_field_value(&mut self, fv: &FieldAndValue) {
         match fv.value {
-            ValueTypes::None => (),
-            ValueTypes::v_u64(u) => {
+            ValueTypes::U64(u) => {
                 self.add_u64(fv.field_name, *u, OutType::Default, 0);
             }
-            ValueTypes::v_i64(i) => {
+            ValueTypes::I64(i) => {
                 self.add_i64(fv.field_name, *i, OutType::Default, 0);
             }
-            ValueTypes::v_u128(u) => {
+            ValueTypes::U128(u) => {
                 self.add_binary(fv.field_name, u.to_le_bytes(), OutType::Default, 0);
             }
-            ValueTypes::v_i128(i) => {
+            ValueTypes::I128(i) => {
                 self.add_binary(fv.field_name, i.to_le_bytes(), OutType::Default, 0);
             }
-            ValueTypes::v_f64(f) => {
+            ValueTypes::F64(f) => {
                 self.add_f64(fv.field_name, *f, OutType::Default, 0);
             }
-            ValueTypes::v_bool(b) => {
+            ValueTypes::Bool(b) => {
                 self.add_bool32(fv.field_name, *b as i32, OutType::Default, 0);
             }
-            ValueTypes::v_str(ref s) => {
+            ValueTypes::Str(ref s) => {
                 self.add_str8(fv.field_name, s.as_ref(), OutType::Utf8, 0);
             }
-            ValueTypes::v_char(c) => {
+            ValueTypes::Char(c) => {
                 // Or add_str16 with a 1-char (BMP) or 2-char (surrogate-pair) string.
                 self.add_u16(fv.field_name, *c as u16, OutType::String, 0);
             }
+            ValueTypes::Error(ref e) => {
+                self.add_struct(fv.field_name, 2, 0);
+                self.add_str8("message", &e.message, OutType::Utf8, 0);
+                self.add_str8_sequence("sources", &e.sources, OutType::Utf8, 0);
+            }
         }
     }
 }
 
+fn add_metadata_fields(
+    eb: &mut EventBuilder,
+    metadata: &tracing::Metadata<'static>,
+    metadata_fields: MetadataFields,
+) {
+    if let Some(file) = metadata_fields.file(metadata) {
+        eb.add_str8("file", file, OutType::Utf8, 0);
+    }
+    if let Some(line) = metadata_fields.line(metadata) {
+        eb.add_u32("line", line, OutType::Default, 0);
+    }
+    if let Some(module_path) = metadata_fields.module_path(metadata) {
+        eb.add_str8("module_path", module_path, OutType::Utf8, 0);
+    }
+    if let Some(target) = metadata_fields.target(metadata) {
+        eb.add_str8("target", target, OutType::Utf8, 0);
+    }
+}
+
+fn add_thread_fields(eb: &mut EventBuilder, thread_fields: ThreadFields) {
+    if let Some(thread_id) = thread_fields.thread_id() {
+        eb.add_u64("thread_id", thread_id, OutType::Default, 0);
+    }
+    if let Some(thread_name) = thread_fields.thread_name() {
+        eb.add_str8("thread_name", thread_name.as_ref(), OutType::Utf8, 0);
+    }
+    if let Some(process_id) = thread_fields.process_id() {
+        eb.add_u32("process_id", process_id, OutType::Default, 0);
+    }
+}
+
 #[doc(hidden)]
 pub struct Provider {
     provider: tracelogging_dynamic::Provider,
@@ -84,19 +119,13 @@ fn callback_fn(
 }
 
 impl Provider {
-    #[inline(always)]
-    fn get_provider(self: Pin<&Self>) -> Pin<&tracelogging_dynamic::Provider> {
-        unsafe { self.map_unchecked(|s| &s.provider) }
-    }
-}
-
-impl super::EventWriter for Provider {
-    fn new<G>(
+    pub(crate) fn new<G>(
         provider_name: &str,
         provider_id: &G,
         provider_group: &ProviderGroup,
-        _default_keyword: u64,
-    ) -> Pin<Arc<Self>>
+        _keywords: &[u64],
+        _timestamp_clock: crate::native::TimestampClock,
+    ) -> (Pin<Arc<Self>>, Result<(), BuildError>)
     where
         for<'a> &'a G: Into<crate::native::GuidWrapper>,
     {
@@ -114,194 +143,170 @@ impl super::EventWriter for Provider {
                 &provider_id.into().into(),
             ),
         });
-        unsafe {
-            wrapper.as_ref().get_provider().register();
-        }
+        let result = unsafe { wrapper.as_ref().get_provider().register() };
 
-        wrapper
-    }
+        let registration = if result == 0 {
+            Ok(())
+        } else {
+            Err(BuildError::RegistrationFailed(result as i32))
+        };
 
-    #[inline]
-    fn enabled(&self, level: u8, keyword: u64) -> bool {
-        self.provider
-            .enabled(tracelogging::Level::from_int(level), keyword)
+        (wrapper, registration)
     }
 
     #[inline(always)]
-    fn supports_enable_callback() -> bool {
-        true
-    }
-
-    fn span_start<'a, 'b, R>(
+    fn write_span_event(
         self: Pin<&Self>,
-        span: &'b SpanRef<'a, R>,
+        span: &SpanRecord<'_>,
+        opcode: Opcode,
+        time_field_name: &str,
         timestamp: SystemTime,
-        activity_id: &[u8; 16],
-        related_activity_id: &[u8; 16],
-        fields: &'b [crate::values::FieldValueIndex],
-        level: u8,
-        keyword: u64,
-        event_tag: u32,
-    ) where
-        R: LookupSpan<'a>,
-    {
-        let span_name = span.name();
-
+        metadata_fields: MetadataFields,
+    ) {
         EBW.with(|eb| {
             let mut eb = eb.borrow_mut();
 
-            eb.reset(span_name, level.into(), keyword, event_tag);
-            eb.opcode(Opcode::Start);
+            eb.reset(span.name, span.level.into(), span.keyword, span.event_tag);
+            eb.opcode(opcode);
 
             eb.add_systemtime(
-                "start time",
+                time_field_name,
                 &Into::<Win32SystemTime>::into(timestamp).st,
                 OutType::DateTimeUtc,
                 0,
             );
 
-            for f in fields {
+            add_metadata_fields(eb.deref_mut(), span.metadata, metadata_fields);
+            add_thread_fields(eb.deref_mut(), span.thread_fields);
+
+            for f in span.fields {
                 <&mut EventBuilder as AddFieldAndValue<EventBuilder>>::add_field_value(
                     &mut eb.deref_mut(),
-                    &FieldAndValue {
-                        field_name: f.field,
-                        value: &f.value,
-                    },
+                    &f,
                 );
             }
 
-            let act = tracelogging_dynamic::Guid::from_bytes_le(activity_id);
-            let related = tracelogging_dynamic::Guid::from_bytes_le(related_activity_id);
-            let _ = eb.write(
-                &self.get_provider(),
-                if activity_id[0] != 0 {
-                    Some(&act)
-                } else {
-                    None
-                },
-                if related_activity_id[0] != 0 {
-                    Some(&related)
-                } else {
-                    None
-                },
-            );
+            let act = tracelogging_dynamic::Guid::from_bytes_le(span.activity_id);
+            let related = span
+                .related_activity_id
+                .map(tracelogging_dynamic::Guid::from_bytes_le);
+            let _ = eb.write(&self.get_provider(), Some(&act), related.as_ref());
         });
     }
 
-    fn span_stop<'a, 'b, R>(
-        self: Pin<&Self>,
-        span: &'b SpanRef<'a, R>,
-        start_stop_times: (std::time::SystemTime, std::time::SystemTime),
-        activity_id: &[u8; 16],
-        related_activity_id: &[u8; 16],
-        fields: &'b [crate::values::FieldValueIndex],
-        level: u8,
-        keyword: u64,
-        event_tag: u32,
-    ) where
-        R: LookupSpan<'a>,
-    {
-        let span_name = span.name();
-
-        EBW.with(|eb| {
-            let mut eb = eb.borrow_mut();
-
-            eb.reset(span_name, level.into(), keyword, event_tag);
-            eb.opcode(Opcode::Stop);
+    fn get_provider(self: Pin<&Self>) -> Pin<&tracelogging_dynamic::Provider> {
+        unsafe { self.map_unchecked(|s| &s.provider) }
+    }
+}
 
-            eb.add_systemtime(
-                "stop time",
-                &Into::<Win32SystemTime>::into(start_stop_times.1).st,
-                OutType::DateTimeUtc,
-                0,
-            );
+impl super::EventWriter for Provider {
+    #[inline]
+    fn enabled(&self, level: u8, keyword: u64) -> bool {
+        self.provider
+            .enabled(tracelogging::Level::from_int(level), keyword)
+    }
 
-            for f in fields {
-                <&mut EventBuilder as AddFieldAndValue<EventBuilder>>::add_field_value(
-                    &mut eb.deref_mut(),
-                    &FieldAndValue {
-                        field_name: f.field,
-                        value: &f.value,
-                    },
-                );
-            }
+    #[inline(always)]
+    fn supports_enable_callback() -> bool {
+        true
+    }
 
-            let act = tracelogging_dynamic::Guid::from_bytes_le(activity_id);
-            let related = tracelogging_dynamic::Guid::from_bytes_le(related_activity_id);
-            let _ = eb.write(
-                &self.get_provider(),
-                if activity_id[0] != 0 {
-                    Some(&act)
-                } else {
-                    None
-                },
-                if related_activity_id[0] != 0 {
-                    Some(&related)
-                } else {
-                    None
-                },
-            );
-        });
+    fn span_start(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        self.write_span_event(
+            span,
+            Opcode::Start,
+            "start time",
+            timestamp,
+            span.metadata_fields,
+        );
     }
 
-    fn write_record(
+    fn span_stop(
         self: Pin<&Self>,
-        timestamp: SystemTime,
-        current_span: u64,
-        parent_span: u64,
-        event_name: &str,
-        level: u8,
-        keyword: u64,
-        event: &tracing::Event<'_>,
+        span: &SpanRecord<'_>,
+        start_stop_times: (std::time::SystemTime, std::time::SystemTime),
     ) {
-        let mut activity_id: [u8; 16] = *GLOBAL_ACTIVITY_SEED;
-        activity_id[0] = if current_span != 0 {
-            let (_, half) = activity_id.split_at_mut(8);
-            half.copy_from_slice(&current_span.to_le_bytes());
-            1
-        } else {
-            0
-        };
+        self.write_span_event(
+            span,
+            Opcode::Stop,
+            "stop time",
+            start_stop_times.1,
+            MetadataFields::default(),
+        );
+    }
 
-        let mut related_activity_id: [u8; 16] = *GLOBAL_ACTIVITY_SEED;
-        related_activity_id[0] = if parent_span != 0 {
-            let (_, half) = related_activity_id.split_at_mut(8);
-            half.copy_from_slice(&parent_span.to_le_bytes());
-            1
-        } else {
-            0
+    fn span_resume(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        self.write_span_event(
+            span,
+            Opcode::Resume,
+            "time",
+            timestamp,
+            MetadataFields::default(),
+        );
+    }
+
+    fn span_suspend(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        self.write_span_event(
+            span,
+            Opcode::Suspend,
+            "time",
+            timestamp,
+            MetadataFields::default(),
+        );
+    }
+
+    fn span_link(self: Pin<&Self>, span: &SpanRecord<'_>, link: &SpanLink, timestamp: SystemTime) {
+        // A transfer event, relating the span's activity to the one it follows from
+        let span = SpanRecord {
+            related_activity_id: Some(&link.activity_id),
+            fields: Fields::new(&[]),
+            ..*span
         };
+        self.write_span_event(
+            &span,
+            Opcode::Receive,
+            "time",
+            timestamp,
+            MetadataFields::default(),
+        );
+    }
 
+    fn write_record(self: Pin<&Self>, event: &EventRecord<'_>) {
         EBW.with(|eb| {
             let mut eb = eb.borrow_mut();
 
-            eb.reset(event_name, level.into(), keyword, 0);
-            eb.opcode(Opcode::Info);
+            eb.reset(
+                event.name,
+                event.level.into(),
+                event.keyword,
+                event.event_tag,
+            );
+            eb.opcode(Opcode::from_int(event.opcode));
 
             eb.add_systemtime(
                 "time",
-                &Into::<Win32SystemTime>::into(timestamp).st,
+                &Into::<Win32SystemTime>::into(event.timestamp).st,
                 OutType::DateTimeUtc,
                 0,
             );
 
-            event.record(&mut VisitorWrapper::from(eb.deref_mut()));
-
-            let act = tracelogging_dynamic::Guid::from_bytes_le(&activity_id);
-            let related = tracelogging_dynamic::Guid::from_bytes_le(&related_activity_id);
-            let _ = eb.write(
-                &self.get_provider(),
-                if activity_id[0] != 0 {
-                    Some(&act)
-                } else {
-                    None
-                },
-                if related_activity_id[0] != 0 {
-                    Some(&related)
-                } else {
-                    None
-                },
-            );
+            add_metadata_fields(eb.deref_mut(), event.metadata, event.metadata_fields);
+            add_thread_fields(eb.deref_mut(), event.thread_fields);
+
+            for f in event.fields {
+                <&mut EventBuilder as AddFieldAndValue<EventBuilder>>::add_field_value(
+                    &mut eb.deref_mut(),
+                    &f,
+                );
+            }
+
+            let act = event
+                .activity_id
+                .map(tracelogging_dynamic::Guid::from_bytes_le);
+            let related = event
+                .related_activity_id
+                .map(tracelogging_dynamic::Guid::from_bytes_le);
+            let _ = eb.write(&self.get_provider(), act.as_ref(), related.as_ref());
         });
     }
 }
@@ -22,6 +22,14 @@ pub use user_events::Provider;
 #[cfg(feature = "common_schema")]
 pub(crate) mod common_schema;
 
+mod thread;
+pub use thread::ThreadFields;
+
+use std::{marker::PhantomData, pin::Pin, sync::Arc, time::SystemTime};
+
+use crate::values::Fields;
+use crate::BuildError;
+
 #[doc(hidden)]
 pub struct GuidWrapper(u128);
 
@@ -59,65 +67,306 @@ pub enum ProviderGroup {
     Linux(std::borrow::Cow<'static, str>),
 }
 
-#[doc(hidden)]
-pub trait EventWriter {
-    fn new<G>(
-        provider_name: &str,
-        provider_id: &G,
-        provider_group: &ProviderGroup,
-        _default_keyword: u64,
-    ) -> std::pin::Pin<std::sync::Arc<Self>>
-    where
-        for<'a> &'a G: Into<GuidWrapper>;
+/// The clock used for the timestamp written in the payload of user_events events.
+///
+/// The timestamp is written as an unsigned 64-bit integer field with the default
+/// format, so decoders show it as a plain number of nanoseconds. Events have a
+/// `time` field, span start events a `start time` field and span stop events a
+/// `stop time` field.
+///
+/// ETW events, and Common Schema events on either platform, are not affected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampClock {
+    /// Nanoseconds since the Unix epoch, in UTC.
+    #[default]
+    WallClock,
+    /// Nanoseconds of `CLOCK_MONOTONIC`, the clock perf uses for its own timestamps.
+    /// The clock is read when the layer sees the span or event, not when it is written.
+    Monotonic,
+    /// Do not write a timestamp field, and rely on the timestamp in the kernel's event header.
+    Omit,
+}
+
+/// Read `CLOCK_MONOTONIC`, in nanoseconds.
+#[cfg(target_os = "linux")]
+pub(crate) fn monotonic_nanos() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // CLOCK_MONOTONIC is always available, and ts is a valid timespec to write to.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// The W3C trace context of a span.
+///
+/// With the `opentelemetry` feature enabled, spans that also carry an
+/// OpenTelemetry span context take their trace ID, span ID and sampling
+/// decision from it. Otherwise the layer generates a trace ID for each root
+/// span and uses `tracing` span IDs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TraceContext {
+    /// The W3C trace ID. Spans inherit the trace ID of their parent.
+    pub trace_id: [u8; 16],
+    /// The W3C span ID. Random for each span, unless taken from an OpenTelemetry span context.
+    pub span_id: [u8; 8],
+    /// The W3C span ID of the span's parent, if it has one.
+    /// The parent may belong to another process.
+    pub parent_span_id: Option<[u8; 8]>,
+    /// Whether the trace is sampled. Always true for spans without an OpenTelemetry span context.
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// The W3C `trace-flags` byte for this context.
+    pub fn trace_flags(&self) -> u8 {
+        self.sampled as u8
+    }
+}
+
+/// A span that another span follows from, recorded with `Span::follows_from`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SpanLink {
+    /// The activity ID of the span being followed.
+    pub activity_id: [u8; 16],
+    /// The W3C trace context of the span being followed.
+    pub trace_context: TraceContext,
+}
+
+/// Which parts of the callsite metadata to write with each event and span start.
+///
+/// Each part is only written when the builder enabled it and the callsite has it.
+/// Native events write them as the `file`, `line`, `module_path` and `target` fields.
+/// Common Schema events write the target as the PartA `name`, and the rest as the
+/// PartB `code.filepath`, `code.lineno` and `code.namespace` fields.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MetadataFields {
+    pub file: bool,
+    pub line: bool,
+    pub module_path: bool,
+    pub target: bool,
+}
+
+impl MetadataFields {
+    #[inline]
+    pub fn file(&self, metadata: &tracing::Metadata<'static>) -> Option<&'static str> {
+        self.file.then(|| metadata.file()).flatten()
+    }
+
+    #[inline]
+    pub fn line(&self, metadata: &tracing::Metadata<'static>) -> Option<u32> {
+        self.line.then(|| metadata.line()).flatten()
+    }
+
+    #[inline]
+    pub fn module_path(&self, metadata: &tracing::Metadata<'static>) -> Option<&'static str> {
+        self.module_path.then(|| metadata.module_path()).flatten()
+    }
+
+    #[inline]
+    pub fn target(&self, metadata: &tracing::Metadata<'static>) -> Option<&'static str> {
+        self.target.then(|| metadata.target())
+    }
+}
 
+/// A span that is being started or stopped, as passed to an [`EventWriter`].
+#[non_exhaustive]
+pub struct SpanRecord<'a> {
+    /// The span's name.
+    pub name: &'static str,
+    /// The span's callsite metadata.
+    pub metadata: &'static tracing::Metadata<'static>,
+    /// The `tracing` ID of the span.
+    pub span_id: u64,
+    /// The `tracing` ID of the span's parent, or 0 if it has no parent.
+    pub parent_span_id: u64,
+    /// The activity ID assigned to the span.
+    pub activity_id: &'a [u8; 16],
+    /// The activity ID of the span's parent, if it has one.
+    pub related_activity_id: Option<&'a [u8; 16]>,
+    /// The W3C trace context of the span.
+    pub trace_context: &'a TraceContext,
+    /// The ETW / EventHeader level, as mapped from the `tracing` level.
+    pub level: u8,
+    pub keyword: u64,
+    pub event_tag: u32,
+    /// The span's fields. Fields that have not been recorded yet are skipped.
+    pub fields: Fields<'a>,
+    /// The callsite metadata to write with the span's start event.
+    pub metadata_fields: MetadataFields,
+    /// The thread and process details to write with each of the span's events.
+    pub thread_fields: ThreadFields,
+    /// Nanoseconds of `CLOCK_MONOTONIC` when the span was started, stopped or
+    /// otherwise changed, if the builder chose [`TimestampClock::Monotonic`].
+    /// Always `None` on platforms other than Linux.
+    pub monotonic_timestamp: Option<u64>,
+}
+
+/// An event, as passed to an [`EventWriter`].
+#[non_exhaustive]
+pub struct EventRecord<'a> {
+    /// The name to give the event.
+    pub name: &'a str,
+    /// The event's callsite metadata.
+    pub metadata: &'static tracing::Metadata<'static>,
+    pub timestamp: std::time::SystemTime,
+    /// Nanoseconds of `CLOCK_MONOTONIC` when the event occurred, if the builder
+    /// chose [`TimestampClock::Monotonic`]. Always `None` on platforms other than Linux.
+    pub monotonic_timestamp: Option<u64>,
+    /// The `tracing` ID of the span the event occurred in, or 0 if there is none.
+    pub span_id: u64,
+    /// The `tracing` ID of that span's parent, or 0 if there is none.
+    pub parent_span_id: u64,
+    /// The activity ID of the span the event occurred in, if there is one.
+    pub activity_id: Option<&'a [u8; 16]>,
+    /// The activity ID of that span's parent, if there is one.
+    pub related_activity_id: Option<&'a [u8; 16]>,
+    /// The W3C trace context of the span the event occurred in, if there is one.
+    pub trace_context: Option<&'a TraceContext>,
+    /// The ETW / EventHeader level, as mapped from the `tracing` level.
+    pub level: u8,
+    pub keyword: u64,
+    pub event_tag: u32,
+    /// The ETW / EventHeader opcode. This is 0 (info) unless the event sets an `etw.opcode` field.
+    pub opcode: u8,
+    /// The event's fields.
+    pub fields: Fields<'a>,
+    /// The callsite metadata to write with the event.
+    pub metadata_fields: MetadataFields,
+    /// The thread and process details to write with the event.
+    pub thread_fields: ThreadFields,
+}
+
+/// A backend that writes the layer's spans and events.
+///
+/// The layer takes care of span bookkeeping, activity IDs and filtering,
+/// and hands each span start, span stop and event to the backend fully
+/// resolved. Implement this trait and pass the backend to
+/// [`LayerBuilder::with_backend`](crate::LayerBuilder::with_backend) to send
+/// the same instrumentation somewhere other than ETW or user_events.
+///
+/// Levels are ETW / EventHeader levels, where 1 is critical and 5 is verbose.
+/// `tracing::Level::TRACE` is mapped to 6.
+///
+/// See `examples/custom_backend.rs` for a complete backend.
+pub trait EventWriter {
+    /// Returns true if an event with the given level and keyword should be written.
     fn enabled(&self, level: u8, keyword: u64) -> bool;
 
+    /// Returns true if the result of [`EventWriter::enabled`] can only change
+    /// when the backend calls `tracing::callsite::rebuild_interest_cache`.
+    /// Otherwise, `enabled` is checked every time a span or event is created.
     fn supports_enable_callback() -> bool;
 
-    fn span_start<'a, 'b, R>(
-        self: std::pin::Pin<&Self>,
-        span: &'b tracing_subscriber::registry::SpanRef<'a, R>,
-        timestamp: std::time::SystemTime,
-        activity_id: &[u8; 16],
-        related_activity_id: &[u8; 16],
-        fields: &'b [crate::values::FieldValueIndex],
-        level: u8,
-        keyword: u64,
-        event_tag: u32,
-    ) where
-        R: tracing_subscriber::registry::LookupSpan<'a>;
-
-    fn span_stop<'a, 'b, R>(
-        self: std::pin::Pin<&Self>,
-        span: &'b tracing_subscriber::registry::SpanRef<'a, R>,
-        start_stop_times: (std::time::SystemTime, std::time::SystemTime),
-        activity_id: &[u8; 16],
-        related_activity_id: &[u8; 16],
-        fields: &'b [crate::values::FieldValueIndex],
-        level: u8,
-        keyword: u64,
-        event_tag: u32,
-    ) where
-        R: tracing_subscriber::registry::LookupSpan<'a>;
-
-    fn write_record(
-        self: std::pin::Pin<&Self>,
-        timestamp: std::time::SystemTime,
-        current_span: u64,
-        parent_span: u64,
-        event_name: &str,
-        level: u8,
-        keyword: u64,
-        event: &tracing::Event<'_>,
+    /// A span started. Depending on the [`SpanMode`](crate::SpanMode), this is
+    /// each time the span is entered, when it is created, or when it is first entered.
+    fn span_start(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime);
+
+    /// A span stopped. Depending on the [`SpanMode`](crate::SpanMode), this is
+    /// each time the span is exited, or when it is closed.
+    fn span_stop(
+        self: Pin<&Self>,
+        span: &SpanRecord<'_>,
+        start_stop_times: (SystemTime, SystemTime),
     );
+
+    /// A started span was entered again. Only used with [`SpanMode::Async`](crate::SpanMode::Async).
+    fn span_resume(self: Pin<&Self>, _span: &SpanRecord<'_>, _timestamp: SystemTime) {}
+
+    /// A started span was exited, and may be entered again before it stops.
+    /// Only used with [`SpanMode::Async`](crate::SpanMode::Async).
+    fn span_suspend(self: Pin<&Self>, _span: &SpanRecord<'_>, _timestamp: SystemTime) {}
+
+    /// The span was recorded as following from another span.
+    fn span_link(
+        self: Pin<&Self>,
+        _span: &SpanRecord<'_>,
+        _link: &SpanLink,
+        _timestamp: SystemTime,
+    ) {
+    }
+
+    /// An event was logged.
+    fn write_record(self: Pin<&Self>, event: &EventRecord<'_>);
 }
 
 #[doc(hidden)]
 pub trait EventMode {
-    type Provider;
+    type Provider: EventWriter + 'static;
+    /// What the builder holds to create its provider: nothing for the built-in
+    /// providers, or the caller's backend.
+    type Backend: Clone;
+
+    /// Whether the provider is registered with ETW or user_events, so its name, ID
+    /// and group must be ones the OS accepts.
+    const REGISTERS_PROVIDER: bool = true;
+
+    fn new_provider<G>(
+        backend: &Self::Backend,
+        provider_name: &str,
+        provider_id: &G,
+        provider_group: &ProviderGroup,
+        keywords: &[u64],
+        timestamp_clock: TimestampClock,
+    ) -> (Pin<Arc<Self::Provider>>, Result<(), BuildError>)
+    where
+        for<'a> &'a G: Into<GuidWrapper>;
 }
 
 #[doc(hidden)]
 impl EventMode for Provider {
     type Provider = Provider;
+    type Backend = ();
+
+    fn new_provider<G>(
+        _backend: &(),
+        provider_name: &str,
+        provider_id: &G,
+        provider_group: &ProviderGroup,
+        keywords: &[u64],
+        timestamp_clock: TimestampClock,
+    ) -> (Pin<Arc<Self::Provider>>, Result<(), BuildError>)
+    where
+        for<'a> &'a G: Into<GuidWrapper>,
+    {
+        Provider::new(
+            provider_name,
+            provider_id,
+            provider_group,
+            keywords,
+            timestamp_clock,
+        )
+    }
+}
+
+/// Marks a builder whose backend was supplied by the caller.
+#[doc(hidden)]
+pub struct CustomBackend<B>(PhantomData<B>);
+
+impl<B> EventMode for CustomBackend<B>
+where
+    B: EventWriter + 'static,
+{
+    type Provider = B;
+    type Backend = Pin<Arc<B>>;
+
+    const REGISTERS_PROVIDER: bool = false;
+
+    fn new_provider<G>(
+        backend: &Pin<Arc<B>>,
+        _provider_name: &str,
+        _provider_id: &G,
+        _provider_group: &ProviderGroup,
+        _keywords: &[u64],
+        _timestamp_clock: TimestampClock,
+    ) -> (Pin<Arc<Self::Provider>>, Result<(), BuildError>)
+    where
+        for<'a> &'a G: Into<GuidWrapper>,
+    {
+        (backend.clone(), Ok(()))
+    }
 }
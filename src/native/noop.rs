@@ -1,10 +1,9 @@
 use std::{pin::Pin, sync::Arc, time::SystemTime};
 
-use tracing_subscriber::registry::{LookupSpan, SpanRef};
-
 use crate::values::*;
+use crate::BuildError;
 
-use crate::native::ProviderGroup;
+use crate::native::{EventRecord, ProviderGroup, SpanRecord};
 
 pub(crate) struct EventBuilderWrapper<'a> {
     _p: core::marker::PhantomData<&'a u8>,
@@ -17,19 +16,22 @@ impl<T> AddFieldAndValue<T> for EventBuilderWrapper<'_> {
 #[doc(hidden)]
 pub struct Provider;
 
-impl crate::native::EventWriter for Provider {
-    fn new<G>(
+impl Provider {
+    pub(crate) fn new<G>(
         _provider_name: &str,
         _provider_id: &G,
         _provider_group: &ProviderGroup,
-        _default_keyword: u64,
-    ) -> Pin<Arc<Self>>
+        _keywords: &[u64],
+        _timestamp_clock: crate::native::TimestampClock,
+    ) -> (Pin<Arc<Self>>, Result<(), BuildError>)
     where
         for<'a> &'a G: Into<crate::native::GuidWrapper>,
     {
-        Arc::pin(Self)
+        (Arc::pin(Self), Ok(()))
     }
+}
 
+impl crate::native::EventWriter for Provider {
     #[inline(always)]
     fn enabled(&self, _level: u8, _keyword: u64) -> bool {
         false
@@ -40,45 +42,14 @@ impl crate::native::EventWriter for Provider {
         false
     }
 
-    fn span_start<'a, 'b, R>(
-        self: Pin<&Self>,
-        _span: &'b SpanRef<'a, R>,
-        _timestamp: SystemTime,
-        _activity_id: &[u8; 16],
-        _related_activity_id: &[u8; 16],
-        _fields: &'b [crate::values::FieldValueIndex],
-        _level: u8,
-        _keyword: u64,
-        _event_tag: u32,
-    ) where
-        R: LookupSpan<'a>,
-    {
-    }
+    fn span_start(self: Pin<&Self>, _span: &SpanRecord<'_>, _timestamp: SystemTime) {}
 
-    fn span_stop<'a, 'b, R>(
+    fn span_stop(
         self: Pin<&Self>,
-        _span: &'b SpanRef<'a, R>,
+        _span: &SpanRecord<'_>,
         _start_stop_times: (std::time::SystemTime, std::time::SystemTime),
-        _activity_id: &[u8; 16],
-        _related_activity_id: &[u8; 16],
-        _fields: &'b [crate::values::FieldValueIndex],
-        _level: u8,
-        _keyword: u64,
-        _event_tag: u32,
-    ) where
-        R: LookupSpan<'a>,
-    {
-    }
-
-    fn write_record(
-        self: Pin<&Self>,
-        _timestamp: SystemTime,
-        _current_span: u64,
-        _parent_span: u64,
-        _event_name: &str,
-        _level: u8,
-        _keyword: u64,
-        _event: &tracing::Event<'_>,
     ) {
     }
+
+    fn write_record(self: Pin<&Self>, _event: &EventRecord<'_>) {}
 }
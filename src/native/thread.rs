@@ -0,0 +1,136 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Which details of the writing thread and process to write with each event and span event.
+///
+/// Native events write them as the `thread_id`, `thread_name` and `process_id` fields.
+/// Common Schema has no PartA extension for them, so Common Schema events write them
+/// as the PartB `thread.id`, `thread.name` and `process.pid` fields.
+///
+/// The values are read once per thread and cached, and describe the thread that
+/// writes the event, which for a span stop event may not be the thread that started it.
+/// On Linux the IDs are read again after a `fork`, so a child process writes its own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ThreadFields {
+    pub thread_id: bool,
+    pub thread_name: bool,
+    pub process_id: bool,
+}
+
+/// Incremented in the child process after each fork, so cached IDs are read again.
+static FORK_GENERATION: AtomicU32 = AtomicU32::new(0);
+
+struct ThreadInfo {
+    // The fork generation the IDs were read in
+    generation: Cell<u32>,
+    thread_id: Cell<u64>,
+    process_id: Cell<u32>,
+    thread_name: Option<Arc<str>>,
+}
+
+impl ThreadInfo {
+    fn new() -> Self {
+        register_fork_handler();
+
+        ThreadInfo {
+            generation: Cell::new(FORK_GENERATION.load(Ordering::Relaxed)),
+            thread_id: Cell::new(os_thread_id()),
+            process_id: Cell::new(std::process::id()),
+            thread_name: std::thread::current().name().map(Arc::from),
+        }
+    }
+
+    /// The thread and process IDs, read again if the process has forked since they were cached.
+    fn ids(&self) -> (u64, u32) {
+        let generation = FORK_GENERATION.load(Ordering::Relaxed);
+        if self.generation.get() != generation {
+            self.generation.set(generation);
+            self.thread_id.set(os_thread_id());
+            self.process_id.set(std::process::id());
+        }
+
+        (self.thread_id.get(), self.process_id.get())
+    }
+}
+
+thread_local! {
+    static THREAD_INFO: ThreadInfo = ThreadInfo::new();
+}
+
+#[cfg(target_os = "linux")]
+fn register_fork_handler() {
+    static REGISTER: std::sync::Once = std::sync::Once::new();
+
+    extern "C" fn after_fork_in_child() {
+        // Only async-signal-safe work is allowed here
+        FORK_GENERATION.fetch_add(1, Ordering::Relaxed);
+    }
+
+    REGISTER.call_once(|| {
+        // Registration only fails if memory runs out, and then the IDs are never refreshed.
+        unsafe {
+            libc::pthread_atfork(None, None, Some(after_fork_in_child));
+        }
+    });
+}
+
+#[cfg(not(target_os = "linux"))]
+fn register_fork_handler() {}
+
+#[cfg(target_os = "linux")]
+fn os_thread_id() -> u64 {
+    // gettid cannot fail.
+    unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+}
+
+#[cfg(target_os = "windows")]
+fn os_thread_id() -> u64 {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+    }
+
+    unsafe { GetCurrentThreadId() as u64 }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+fn os_thread_id() -> u64 {
+    0
+}
+
+impl ThreadFields {
+    /// The OS thread ID of the current thread, if it should be written.
+    #[inline]
+    pub fn thread_id(&self) -> Option<u64> {
+        if self.thread_id {
+            THREAD_INFO.try_with(|info| info.ids().0).ok()
+        } else {
+            None
+        }
+    }
+
+    /// The name of the current thread, if it should be written and the thread has one.
+    #[inline]
+    pub fn thread_name(&self) -> Option<Arc<str>> {
+        if self.thread_name {
+            THREAD_INFO
+                .try_with(|info| info.thread_name.clone())
+                .ok()
+                .flatten()
+        } else {
+            None
+        }
+    }
+
+    /// The ID of the current process, if it should be written.
+    #[inline]
+    pub fn process_id(&self) -> Option<u32> {
+        if self.process_id {
+            THREAD_INFO.try_with(|info| info.ids().1).ok()
+        } else {
+            None
+        }
+    }
+}
@@ -1,51 +1,139 @@
-use crate::{map_level, values::*, GLOBAL_ACTIVITY_SEED};
+use crate::{map_level, values::*, BuildError};
 use eventheader::*;
 use eventheader_dynamic::EventBuilder;
 use std::{cell::RefCell, ops::DerefMut, pin::Pin, sync::Arc, time::SystemTime};
-use tracing_subscriber::registry::{LookupSpan, SpanRef};
 
-use super::ProviderGroup;
+use super::{
+    monotonic_nanos, EventRecord, MetadataFields, ProviderGroup, SpanLink, SpanRecord,
+    ThreadFields, TimestampClock,
+};
 
 thread_local! {static EBW: std::cell::RefCell<EventBuilder>  = RefCell::new(EventBuilder::new());}
 
 impl<T> AddFieldAndValue<T> for &'_ mut eventheader_dynamic::EventBuilder {
     fn add_field_value(&mut self, fv: &FieldAndValue) {
         match fv.value {
-            ValueTypes::None => (),
-            ValueTypes::v_u64(u) => {
+            ValueTypes::U64(u) => {
                 self.add_value(fv.field_name, *u, FieldFormat::Default, 0);
             }
-            ValueTypes::v_i64(i) => {
+            ValueTypes::I64(i) => {
                 self.add_value(fv.field_name, *i, FieldFormat::SignedInt, 0);
             }
-            ValueTypes::v_u128(u) => {
+            ValueTypes::U128(u) => {
                 self.add_value(fv.field_name, u.to_le_bytes(), FieldFormat::Default, 0);
             }
-            ValueTypes::v_i128(i) => {
+            ValueTypes::I128(i) => {
                 self.add_value(fv.field_name, i.to_le_bytes(), FieldFormat::Default, 0);
             }
-            ValueTypes::v_f64(f) => {
+            ValueTypes::F64(f) => {
                 self.add_value(fv.field_name, *f, FieldFormat::Float, 0);
             }
-            ValueTypes::v_bool(b) => {
+            ValueTypes::Bool(b) => {
                 self.add_value(fv.field_name, *b, FieldFormat::Boolean, 0);
             }
-            ValueTypes::v_str(ref s) => {
+            ValueTypes::Str(ref s) => {
                 self.add_str(fv.field_name, s.as_ref(), FieldFormat::Default, 0);
             }
-            ValueTypes::v_char(c) => {
+            ValueTypes::Char(c) => {
                 self.add_value(fv.field_name, *c, FieldFormat::StringUtf, 0);
             }
+            ValueTypes::Error(ref e) => {
+                self.add_struct(fv.field_name, 2, 0);
+                self.add_str("message", &e.message, FieldFormat::Default, 0);
+                self.add_str_sequence("sources", &e.sources, FieldFormat::Default, 0);
+            }
         }
     }
 }
 
+fn add_metadata_fields(
+    eb: &mut EventBuilder,
+    metadata: &tracing::Metadata<'static>,
+    metadata_fields: MetadataFields,
+) {
+    if let Some(file) = metadata_fields.file(metadata) {
+        eb.add_str("file", file, FieldFormat::Default, 0);
+    }
+    if let Some(line) = metadata_fields.line(metadata) {
+        eb.add_value("line", line, FieldFormat::Default, 0);
+    }
+    if let Some(module_path) = metadata_fields.module_path(metadata) {
+        eb.add_str("module_path", module_path, FieldFormat::Default, 0);
+    }
+    if let Some(target) = metadata_fields.target(metadata) {
+        eb.add_str("target", target, FieldFormat::Default, 0);
+    }
+}
+
+fn add_thread_fields(eb: &mut EventBuilder, thread_fields: ThreadFields) {
+    if let Some(thread_id) = thread_fields.thread_id() {
+        eb.add_value("thread_id", thread_id, FieldFormat::Default, 0);
+    }
+    if let Some(thread_name) = thread_fields.thread_name() {
+        eb.add_str("thread_name", thread_name.as_ref(), FieldFormat::Default, 0);
+    }
+    if let Some(process_id) = thread_fields.process_id() {
+        eb.add_value("process_id", process_id, FieldFormat::Default, 0);
+    }
+}
+
 #[doc(hidden)]
 pub struct Provider {
     provider: std::sync::RwLock<eventheader_dynamic::Provider>,
+    timestamp_clock: TimestampClock,
 }
 
 impl Provider {
+    pub(crate) fn new<G>(
+        provider_name: &str,
+        _: &G,
+        provider_group: &ProviderGroup,
+        keywords: &[u64],
+        timestamp_clock: TimestampClock,
+    ) -> (Pin<Arc<Self>>, Result<(), BuildError>)
+    where
+        for<'a> &'a G: Into<crate::native::GuidWrapper>,
+    {
+        let mut options = eventheader_dynamic::Provider::new_options();
+        if let ProviderGroup::Linux(ref name) = provider_group {
+            options = *options.group_name(name);
+        }
+        let mut provider = eventheader_dynamic::Provider::new(provider_name, &options);
+
+        let mut errno = 0;
+        for keyword in keywords {
+            for level in [
+                tracing::Level::ERROR,
+                tracing::Level::WARN,
+                tracing::Level::INFO,
+                tracing::Level::DEBUG,
+                tracing::Level::TRACE,
+            ] {
+                let es = provider.register_set(
+                    eventheader_dynamic::Level::from_int(map_level(&level)),
+                    *keyword,
+                );
+                if errno == 0 {
+                    errno = es.errno();
+                }
+            }
+        }
+
+        let registration = if errno == 0 {
+            Ok(())
+        } else {
+            Err(BuildError::RegistrationFailed(errno))
+        };
+
+        (
+            Arc::pin(Provider {
+                provider: std::sync::RwLock::new(provider),
+                timestamp_clock,
+            }),
+            registration,
+        )
+    }
+
     fn find_set(
         self: Pin<&Self>,
         level: eventheader_dynamic::Level,
@@ -65,261 +153,188 @@ impl Provider {
             .register_set(level, keyword)
     }
 
-    fn get_provider(self: Pin<&Self>) -> Pin<&std::sync::RwLock<eventheader_dynamic::Provider>> {
-        unsafe { self.map_unchecked(|s| &s.provider) }
-    }
-}
-
-impl crate::native::EventWriter for Provider {
-    fn new<G>(
-        provider_name: &str,
-        _: &G,
-        provider_group: &ProviderGroup,
-        default_keyword: u64,
-    ) -> Pin<Arc<Self>>
-    where
-        for<'a> &'a G: Into<crate::native::GuidWrapper>,
-    {
-        let mut options = eventheader_dynamic::Provider::new_options();
-        if let ProviderGroup::Linux(ref name) = provider_group {
-            options = *options.group_name(&name);
-        }
-        let mut provider = eventheader_dynamic::Provider::new(provider_name, &options);
-
-        provider.register_set(
-            eventheader_dynamic::Level::from_int(map_level(&tracing::Level::ERROR)),
-            default_keyword,
-        );
-        provider.register_set(
-            eventheader_dynamic::Level::from_int(map_level(&tracing::Level::WARN)),
-            default_keyword,
-        );
-        provider.register_set(
-            eventheader_dynamic::Level::from_int(map_level(&tracing::Level::INFO)),
-            default_keyword,
-        );
-        provider.register_set(
-            eventheader_dynamic::Level::from_int(map_level(&tracing::Level::DEBUG)),
-            default_keyword,
-        );
-        provider.register_set(
-            eventheader_dynamic::Level::from_int(map_level(&tracing::Level::TRACE)),
-            default_keyword,
-        );
+    fn add_timestamp(
+        &self,
+        eb: &mut EventBuilder,
+        field_name: &str,
+        timestamp: SystemTime,
+        monotonic_timestamp: Option<u64>,
+    ) {
+        let nanos = match self.timestamp_clock {
+            TimestampClock::WallClock => timestamp
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            // The layer reads the monotonic clock whenever the builder chose it
+            TimestampClock::Monotonic => monotonic_timestamp.unwrap_or_else(monotonic_nanos),
+            TimestampClock::Omit => return,
+        };
 
-        Arc::pin(Provider {
-            provider: std::sync::RwLock::new(provider),
-        })
+        eb.add_value(field_name, nanos, FieldFormat::Default, 0);
     }
 
-    #[inline]
-    fn enabled(&self, level: u8, keyword: u64) -> bool {
-        let es = self
-            .provider
-            .read()
-            .unwrap()
-            .find_set(eventheader_dynamic::Level::from_int(level), keyword);
-        return if let Some(s) = es { s.enabled() } else { false };
-    }
-
-    #[inline(always)]
-    fn supports_enable_callback() -> bool {
-        false
-    }
-
-    fn span_start<'a, 'b, R>(
+    fn write_span_event(
         self: Pin<&Self>,
-        span: &'b SpanRef<'a, R>,
+        span: &SpanRecord<'_>,
+        opcode: Opcode,
+        time_field_name: &str,
         timestamp: SystemTime,
-        activity_id: &[u8; 16],
-        related_activity_id: &[u8; 16],
-        fields: &'b [crate::values::FieldValueIndex],
-        level: u8,
-        keyword: u64,
-        event_tag: u32,
-    ) where
-        R: LookupSpan<'a>,
-    {
-        let span_name = span.name();
-
-        let es = if let Some(es) = self.find_set(level.into(), keyword) {
+        metadata_fields: MetadataFields,
+    ) {
+        let es = if let Some(es) = self.find_set(span.level.into(), span.keyword) {
             es
         } else {
-            self.register_set(level.into(), keyword)
+            self.register_set(span.level.into(), span.keyword)
         };
 
         EBW.with(|eb| {
             let mut eb = eb.borrow_mut();
 
-            eb.reset(span_name, event_tag as u16);
-            eb.opcode(Opcode::ActivityStart);
-
-            eb.add_value(
-                "start time",
-                timestamp
-                    .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-                FieldFormat::Time,
-                0,
+            eb.reset(span.name, span.event_tag as u16);
+            eb.opcode(opcode);
+
+            self.add_timestamp(
+                eb.deref_mut(),
+                time_field_name,
+                timestamp,
+                span.monotonic_timestamp,
             );
+            add_metadata_fields(eb.deref_mut(), span.metadata, metadata_fields);
+            add_thread_fields(eb.deref_mut(), span.thread_fields);
 
-            for f in fields {
+            for f in span.fields {
                 <&mut EventBuilder as AddFieldAndValue<EventBuilder>>::add_field_value(
                     &mut eb.deref_mut(),
-                    &FieldAndValue {
-                        field_name: f.field,
-                        value: &f.value,
-                    },
+                    &f,
                 );
             }
 
-            let _ = eb.write(
-                &es,
-                if activity_id[0] != 0 {
-                    Some(activity_id)
-                } else {
-                    None
-                },
-                if related_activity_id[0] != 0 {
-                    Some(&related_activity_id)
-                } else {
-                    None
-                },
-            );
+            let _ = eb.write(&es, Some(span.activity_id), span.related_activity_id);
         });
     }
 
-    fn span_stop<'a, 'b, R>(
-        self: Pin<&Self>,
-        span: &'b SpanRef<'a, R>,
-        start_stop_times: (std::time::SystemTime, std::time::SystemTime),
-        activity_id: &[u8; 16],
-        related_activity_id: &[u8; 16],
-        fields: &'b [crate::values::FieldValueIndex],
-        level: u8,
-        keyword: u64,
-        event_tag: u32,
-    ) where
-        R: LookupSpan<'a>,
-    {
-        let span_name = span.name();
+    fn get_provider(self: Pin<&Self>) -> Pin<&std::sync::RwLock<eventheader_dynamic::Provider>> {
+        unsafe { self.map_unchecked(|s| &s.provider) }
+    }
+}
 
-        let es = if let Some(es) = self.find_set(level.into(), keyword) {
-            es
+impl crate::native::EventWriter for Provider {
+    #[inline]
+    fn enabled(&self, level: u8, keyword: u64) -> bool {
+        let es = self
+            .provider
+            .read()
+            .unwrap()
+            .find_set(eventheader_dynamic::Level::from_int(level), keyword);
+        if let Some(s) = es {
+            s.enabled()
         } else {
-            self.register_set(level.into(), keyword)
-        };
-
-        EBW.with(|eb| {
-            let mut eb = eb.borrow_mut();
-
-            eb.reset(span_name, event_tag as u16);
-            eb.opcode(Opcode::ActivityStop);
-
-            eb.add_value(
-                "stop time",
-                start_stop_times
-                    .1
-                    .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-                FieldFormat::Time,
-                0,
-            );
+            // Keywords set through a ReloadHandle aren't known when the provider is built.
+            // Register the tracepoint now so a session can enable it for later events.
+            // The layer only passes configured keywords and levels, so this set is bounded.
+            self.provider
+                .write()
+                .unwrap()
+                .register_set(eventheader_dynamic::Level::from_int(level), keyword)
+                .enabled()
+        }
+    }
 
-            for f in fields {
-                <&mut EventBuilder as AddFieldAndValue<EventBuilder>>::add_field_value(
-                    &mut eb.deref_mut(),
-                    &FieldAndValue {
-                        field_name: f.field,
-                        value: &f.value,
-                    },
-                );
-            }
+    #[inline(always)]
+    fn supports_enable_callback() -> bool {
+        false
+    }
 
-            let _ = eb.write(
-                &es,
-                if activity_id[0] != 0 {
-                    Some(activity_id)
-                } else {
-                    None
-                },
-                if related_activity_id[0] != 0 {
-                    Some(&related_activity_id)
-                } else {
-                    None
-                },
-            );
-        });
+    fn span_start(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        self.write_span_event(
+            span,
+            Opcode::ActivityStart,
+            "start time",
+            timestamp,
+            span.metadata_fields,
+        );
     }
 
-    fn write_record(
+    fn span_stop(
         self: Pin<&Self>,
-        timestamp: SystemTime,
-        current_span: u64,
-        parent_span: u64,
-        event_name: &str,
-        level: u8,
-        keyword: u64,
-        event: &tracing::Event<'_>,
+        span: &SpanRecord<'_>,
+        start_stop_times: (std::time::SystemTime, std::time::SystemTime),
     ) {
-        let es = if let Some(es) = self.find_set(level.into(), keyword) {
-            es
-        } else {
-            self.register_set(level.into(), keyword)
-        };
+        self.write_span_event(
+            span,
+            Opcode::ActivityStop,
+            "stop time",
+            start_stop_times.1,
+            MetadataFields::default(),
+        );
+    }
 
-        let mut activity_id: [u8; 16] = *GLOBAL_ACTIVITY_SEED;
-        activity_id[0] = if current_span != 0 {
-            let (_, half) = activity_id.split_at_mut(8);
-            half.copy_from_slice(&current_span.to_le_bytes());
-            1
-        } else {
-            0
+    fn span_resume(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        self.write_span_event(
+            span,
+            Opcode::Resume,
+            "time",
+            timestamp,
+            MetadataFields::default(),
+        );
+    }
+
+    fn span_suspend(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        self.write_span_event(
+            span,
+            Opcode::Suspend,
+            "time",
+            timestamp,
+            MetadataFields::default(),
+        );
+    }
+
+    fn span_link(self: Pin<&Self>, span: &SpanRecord<'_>, link: &SpanLink, timestamp: SystemTime) {
+        // A transfer event, relating the span's activity to the one it follows from
+        let span = SpanRecord {
+            related_activity_id: Some(&link.activity_id),
+            fields: Fields::new(&[]),
+            ..*span
         };
+        self.write_span_event(
+            &span,
+            Opcode::Receive,
+            "time",
+            timestamp,
+            MetadataFields::default(),
+        );
+    }
 
-        let mut related_activity_id: [u8; 16] = *GLOBAL_ACTIVITY_SEED;
-        related_activity_id[0] = if parent_span != 0 {
-            let (_, half) = related_activity_id.split_at_mut(8);
-            half.copy_from_slice(&parent_span.to_le_bytes());
-            1
+    fn write_record(self: Pin<&Self>, event: &EventRecord<'_>) {
+        let es = if let Some(es) = self.find_set(event.level.into(), event.keyword) {
+            es
         } else {
-            0
+            self.register_set(event.level.into(), event.keyword)
         };
 
         EBW.with(|eb| {
             let mut eb = eb.borrow_mut();
 
-            eb.reset(&event_name, 0);
-            eb.opcode(Opcode::Info);
+            eb.reset(event.name, event.event_tag as u16);
+            eb.opcode(Opcode::from_int(event.opcode));
 
-            eb.add_value(
+            self.add_timestamp(
+                eb.deref_mut(),
                 "time",
-                timestamp
-                    .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-                FieldFormat::Time,
-                0,
+                event.timestamp,
+                event.monotonic_timestamp,
             );
+            add_metadata_fields(eb.deref_mut(), event.metadata, event.metadata_fields);
+            add_thread_fields(eb.deref_mut(), event.thread_fields);
 
-            let mut visitor = VisitorWrapper::from(eb.deref_mut());
-            event.record(&mut visitor);
-
-            let _ = eb.write(
-                &es,
-                if activity_id[0] != 0 {
-                    Some(&activity_id)
-                } else {
-                    None
-                },
-                if related_activity_id[0] != 0 {
-                    Some(&related_activity_id)
-                } else {
-                    None
-                },
-            );
+            for f in event.fields {
+                <&mut EventBuilder as AddFieldAndValue<EventBuilder>>::add_field_value(
+                    &mut eb.deref_mut(),
+                    &f,
+                );
+            }
+
+            let _ = eb.write(&es, event.activity_id, event.related_activity_id);
         });
     }
 }
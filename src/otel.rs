@@ -0,0 +1,76 @@
+//! Interop with the span context assigned by `tracing-opentelemetry`.
+//!
+//! When the `opentelemetry` feature is enabled and an `OpenTelemetryLayer` is
+//! registered *before* this layer, each span already has its OpenTelemetry
+//! trace ID and span ID when the ETW layer sees it, and those are used in place
+//! of the layer's own IDs.
+
+use crate::native::TraceContext;
+
+/// Build an activity ID from a W3C trace ID and span ID.
+///
+/// The first 8 bytes of the trace ID are followed by the span ID, so every
+/// activity in a trace shares a common prefix and the related activity ID of
+/// a span can be computed from its parent's span ID alone.
+pub(crate) fn activity_id(trace_id: &[u8; 16], span_id: &[u8; 8]) -> [u8; 16] {
+    let mut activity_id = [0u8; 16];
+    let (trace_half, span_half) = activity_id.split_at_mut(8);
+    trace_half.copy_from_slice(&trace_id[..8]);
+    span_half.copy_from_slice(span_id);
+    activity_id
+}
+
+/// Read the OpenTelemetry span context that `tracing-opentelemetry` assigned to a span.
+///
+/// The sampling decision for a root span is normally made when it is first exported,
+/// after this layer has already seen it, so root spans without a remote parent
+/// are reported as sampled.
+#[cfg(feature = "opentelemetry")]
+pub(crate) fn trace_context<S>(
+    span: &tracing_subscriber::registry::SpanRef<'_, S>,
+) -> Option<TraceContext>
+where
+    S: for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    use opentelemetry::trace::{SamplingDecision, TraceContextExt};
+
+    let extensions = span.extensions();
+    let otel = extensions.get::<tracing_opentelemetry::OtelData>()?;
+    let span_id = otel.builder.span_id?;
+
+    let parent = if otel.parent_cx.has_active_span() {
+        Some(otel.parent_cx.span().span_context().clone())
+    } else {
+        None
+    };
+
+    let trace_id = match (otel.builder.trace_id, &parent) {
+        (Some(trace_id), _) => trace_id,
+        (None, Some(parent)) => parent.trace_id(),
+        (None, None) => return None,
+    };
+
+    let sampled = match (&otel.builder.sampling_result, &parent) {
+        (Some(result), _) => result.decision == SamplingDecision::RecordAndSample,
+        (None, Some(parent)) => parent.is_sampled(),
+        (None, None) => true,
+    };
+
+    Some(TraceContext {
+        trace_id: trace_id.to_bytes(),
+        span_id: span_id.to_bytes(),
+        parent_span_id: parent.map(|parent| parent.span_id().to_bytes()),
+        sampled,
+    })
+}
+
+#[cfg(not(feature = "opentelemetry"))]
+#[inline(always)]
+pub(crate) fn trace_context<S>(
+    _span: &tracing_subscriber::registry::SpanRef<'_, S>,
+) -> Option<TraceContext>
+where
+    S: for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    None
+}
@@ -0,0 +1,157 @@
+//! Carrying activities across process boundaries.
+//!
+//! The sending side exports the current span with [`current_traceparent`] or
+//! [`current_activity_id`] and passes the strings along, for example as an HTTP
+//! header or a command line argument. The receiving side creates a span with the
+//! reserved `etw.traceparent` and `etw.related_activity_id` fields set to those
+//! strings. The span joins the sender's trace and uses the sender's activity as
+//! its related activity. Neither field is written as part of the payload.
+//! Spans that have an OpenTelemetry span context keep the one it was given.
+
+use tracing::span;
+
+use crate::native::TraceContext;
+use crate::values::{FieldValueIndex, ValueTypes};
+
+/// Gives code outside the layer access to a span's activity ID and trace context.
+/// Found through `Dispatch::downcast_ref`, in the same way as `tracing-opentelemetry`
+/// exposes its span contexts.
+pub(crate) struct WithContext(pub(crate) GetContext);
+
+pub(crate) type GetContext =
+    fn(&tracing::Dispatch, &span::Id, &mut dyn FnMut(&[u8; 16], &TraceContext));
+
+fn with_current<T>(f: impl FnOnce(&[u8; 16], &TraceContext) -> T) -> Option<T> {
+    tracing::Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let with_context = dispatch.downcast_ref::<WithContext>()?;
+
+            let mut f = Some(f);
+            let mut result = None;
+            (with_context.0)(dispatch, id, &mut |activity_id, trace_context| {
+                if let Some(f) = f.take() {
+                    result = Some(f(activity_id, trace_context));
+                }
+            });
+            result
+        })
+        .flatten()
+}
+
+/// The activity ID of the current span, formatted as a GUID, such as
+/// `6f9619ff-8b86-d011-b42d-00c04fc964ff`.
+///
+/// Returns `None` if there is no current span, or the ETW layer did not see it.
+pub fn current_activity_id() -> Option<String> {
+    with_current(|activity_id, _| {
+        let guid = tracelogging::Guid::from_bytes_le(activity_id);
+        String::from_utf8_lossy(&guid.to_utf8_bytes()).into_owned()
+    })
+}
+
+/// The W3C trace context of the current span, formatted as a `traceparent` header.
+///
+/// Returns `None` if there is no current span, or the ETW layer did not see it.
+///
+/// ```
+/// use tracing_etw::{capture::EventCapture, current_traceparent, LayerBuilder};
+/// use tracing_subscriber::prelude::*;
+///
+/// let capture = EventCapture::new();
+/// let subscriber = tracing_subscriber::registry()
+///     .with(LayerBuilder::new_capture("test_provider", &capture).build());
+///
+/// tracing::subscriber::with_default(subscriber, || {
+///     let traceparent = {
+///         let _span = tracing::info_span!("send").entered();
+///         current_traceparent().unwrap()
+///     };
+///
+///     // Usually in another process
+///     let _span = tracing::info_span!("receive", etw.traceparent = %traceparent).entered();
+/// });
+///
+/// let events = capture.events();
+/// assert_eq!(
+///     events[2].trace_context.unwrap().trace_id,
+///     events[0].trace_context.unwrap().trace_id
+/// );
+/// ```
+pub fn current_traceparent() -> Option<String> {
+    with_current(|_, trace_context| {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&trace_context.trace_id),
+            hex(&trace_context.span_id),
+            trace_context.trace_flags()
+        )
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 {
+        return None;
+    }
+
+    let mut bytes = [0u8; N];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// The trace ID, span ID and sampled flag of a W3C `traceparent` header.
+fn parse_traceparent(traceparent: &str) -> Option<([u8; 16], [u8; 8], bool)> {
+    let mut parts = traceparent.trim().split('-');
+    let version = parse_hex::<1>(parts.next()?)?;
+    let trace_id = parse_hex::<16>(parts.next()?)?;
+    let span_id = parse_hex::<8>(parts.next()?)?;
+    let flags = parse_hex::<1>(parts.next()?)?;
+
+    // Later versions may only add fields
+    if version[0] == 0xff || (version[0] == 0 && parts.next().is_some()) {
+        return None;
+    }
+
+    if trace_id == [0; 16] || span_id == [0; 8] {
+        return None;
+    }
+
+    Some((trace_id, span_id, flags[0] & 1 != 0))
+}
+
+/// The context a span imported through its reserved propagation fields.
+#[derive(Default)]
+pub(crate) struct RemoteParent {
+    pub(crate) activity_id: Option<[u8; 16]>,
+    pub(crate) trace: Option<([u8; 16], [u8; 8], bool)>,
+}
+
+impl RemoteParent {
+    /// Move the `etw.traceparent` and `etw.related_activity_id` fields out of `fields`.
+    /// Values that cannot be parsed are dropped.
+    pub(crate) fn take_from(fields: &mut [FieldValueIndex]) -> Self {
+        let mut remote = RemoteParent::default();
+
+        for f in fields.iter_mut() {
+            if f.field != "etw.traceparent" && f.field != "etw.related_activity_id" {
+                continue;
+            }
+
+            if let Some(ValueTypes::Str(value)) = f.value.take() {
+                if f.field == "etw.traceparent" {
+                    remote.trace = parse_traceparent(&value);
+                } else {
+                    remote.activity_id =
+                        tracelogging::Guid::try_parse(&value).map(|guid| guid.to_bytes_le());
+                }
+            }
+        }
+
+        remote
+    }
+}
@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use tracing::metadata::LevelFilter;
+use tracing_subscriber::filter::Targets;
+
+use crate::keywords::KeywordMap;
+use crate::BuildError;
+
+#[derive(Clone)]
+pub(crate) struct Settings {
+    keywords: KeywordMap,
+    targets: Option<Targets>,
+    max_level: LevelFilter,
+}
+
+impl Settings {
+    fn new(keywords: KeywordMap) -> Self {
+        Settings {
+            keywords,
+            targets: None,
+            max_level: LevelFilter::TRACE,
+        }
+    }
+}
+
+/// The keywords, targets and levels of a layer, shared with its filter.
+///
+/// Only layers built with a [`ReloadHandle`] pay for settings that can change;
+/// the others read them directly.
+#[derive(Clone)]
+pub(crate) enum FilterSettings {
+    Fixed(Arc<Settings>),
+    #[cfg(not(feature = "global_filter"))]
+    Reloadable(Arc<ArcSwap<Settings>>),
+}
+
+impl FilterSettings {
+    pub(crate) fn new(keywords: KeywordMap) -> Self {
+        FilterSettings::Fixed(Arc::new(Settings::new(keywords)))
+    }
+
+    /// Settings that can be changed through the returned handle. The names in
+    /// `always_enabled` are added to every target filter the handle sets.
+    #[cfg(not(feature = "global_filter"))]
+    pub(crate) fn reloadable(
+        keywords: KeywordMap,
+        always_enabled: Vec<String>,
+    ) -> (Self, ReloadHandle) {
+        let settings = Arc::new(ArcSwap::from_pointee(Settings::new(keywords)));
+        let handle = ReloadHandle {
+            settings: settings.clone(),
+            always_enabled,
+        };
+        (FilterSettings::Reloadable(settings), handle)
+    }
+
+    fn read<R>(&self, f: impl FnOnce(&Settings) -> R) -> R {
+        match self {
+            FilterSettings::Fixed(settings) => f(settings),
+            #[cfg(not(feature = "global_filter"))]
+            FilterSettings::Reloadable(settings) => f(&settings.load()),
+        }
+    }
+
+    /// The keyword for the given target. See [`KeywordMap::keyword`].
+    pub(crate) fn keyword(&self, target: &str) -> u64 {
+        self.read(|settings| settings.keywords.keyword(target))
+    }
+
+    /// The keyword for a span or event from `target` that asked for `requested`
+    /// through an `etw.keyword` field. Only keywords the layer was configured
+    /// with can be requested, so the set of keywords a provider sees is bounded.
+    pub(crate) fn keyword_or_override(&self, target: &str, requested: Option<u64>) -> u64 {
+        self.read(|settings| match requested {
+            Some(keyword) if settings.keywords.contains(keyword) => keyword,
+            _ => settings.keywords.keyword(target),
+        })
+    }
+
+    /// Every keyword that can currently be chosen, starting with the default keyword.
+    pub(crate) fn keywords(&self) -> Vec<u64> {
+        self.read(|settings| settings.keywords.keywords())
+    }
+
+    /// Whether the target and level filters allow spans and events from this callsite.
+    pub(crate) fn allows(&self, metadata: &tracing::Metadata<'_>) -> bool {
+        self.read(|settings| {
+            if settings.max_level < *metadata.level() {
+                return false;
+            }
+
+            settings.targets.as_ref().map_or(true, |targets| {
+                targets.would_enable(metadata.target(), metadata.level())
+            })
+        })
+    }
+}
+
+/// Changes the keywords, targets and levels of a built layer.
+///
+/// Returned alongside the layer by
+/// [`EtwLayerBuilder::build_with_reload`](crate::EtwLayerBuilder::build_with_reload).
+/// Clones of the handle change the same layer. Each change makes `tracing` ask
+/// every callsite again whether it is enabled, so it should not be made per event.
+#[derive(Clone)]
+pub struct ReloadHandle {
+    settings: Arc<ArcSwap<Settings>>,
+    // The provider and group names, which are always enabled in target filters
+    always_enabled: Vec<String>,
+}
+
+impl ReloadHandle {
+    fn update(&self, f: impl Fn(&mut Settings)) {
+        self.settings.rcu(|settings| {
+            let mut settings = Settings::clone(settings);
+            f(&mut settings);
+            settings
+        });
+
+        // Callsites cache whether they are enabled, so they must be asked again
+        tracing::callsite::rebuild_interest_cache();
+    }
+
+    /// Replace the keyword for spans and events whose target has no keyword
+    /// of its own. See [`EtwLayerBuilder::with_default_keyword`](crate::EtwLayerBuilder::with_default_keyword).
+    pub fn set_default_keyword(&self, kw: u64) -> Result<(), BuildError> {
+        if kw == 0 {
+            return Err(BuildError::ZeroKeyword);
+        }
+
+        self.update(|settings| {
+            settings.keywords = KeywordMap::new(
+                kw,
+                settings.keywords.targets(),
+                settings.keywords.overrides(),
+            );
+        });
+        Ok(())
+    }
+
+    /// Replace every target keyword set with
+    /// [`EtwLayerBuilder::with_target_keyword`](crate::EtwLayerBuilder::with_target_keyword).
+    pub fn set_target_keywords(&self, target_keywords: &[(String, u64)]) -> Result<(), BuildError> {
+        if target_keywords.iter().any(|(_, kw)| *kw == 0) {
+            return Err(BuildError::ZeroKeyword);
+        }
+
+        self.update(|settings| {
+            settings.keywords = KeywordMap::new(
+                settings.keywords.default_keyword(),
+                target_keywords,
+                settings.keywords.overrides(),
+            );
+        });
+        Ok(())
+    }
+
+    /// Only pass the layer spans and events from the targets and levels enabled
+    /// by `targets`, and from this provider's name and group name.
+    /// `None` passes every target.
+    pub fn set_targets(&self, targets: Option<Targets>) {
+        let targets = targets.map(|targets| {
+            self.always_enabled.iter().fold(targets, |targets, name| {
+                targets.with_target(name.clone(), LevelFilter::TRACE)
+            })
+        });
+
+        self.update(|settings| settings.targets = targets.clone());
+    }
+
+    /// Only pass the layer spans and events at or above `level`.
+    /// This applies to every target, including the provider's name and group name.
+    pub fn set_max_level(&self, level: LevelFilter) {
+        self.update(|settings| settings.max_level = level);
+    }
+}
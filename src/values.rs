@@ -1,236 +1,455 @@
 use std::borrow::Cow;
+use std::cell::Cell;
 use std::fmt::Write;
 
 use tracing::field;
 
-#[allow(non_camel_case_types)]
-#[derive(Default, Clone)]
-#[doc(hidden)]
+/// The value of a span or event field, as recorded by `tracing`.
+///
+/// Values recorded through `Debug` are formatted and stored as strings.
+#[derive(Clone)]
+#[non_exhaustive]
 pub enum ValueTypes {
-    #[default]
-    None,
-    v_u64(u64),
-    v_i64(i64),
-    v_u128(u128),
-    v_i128(i128),
-    v_f64(f64),
-    v_bool(bool),
-    v_str(Cow<'static, str>), // Would be nice if we didn't have to do a heap allocation
-    v_char(char),
+    U64(u64),
+    I64(i64),
+    U128(u128),
+    I128(i128),
+    F64(f64),
+    Bool(bool),
+    Str(Cow<'static, str>), // Would be nice if we didn't have to do a heap allocation
+    Char(char),
+    Error(Box<ErrorValue>),
+}
+
+/// An error value, recorded with the `Display` strings of its whole `source()` chain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorValue {
+    /// The error's `Display` string.
+    pub message: String,
+    /// The `Display` strings of the error's sources, starting with its immediate `source()`.
+    pub sources: Vec<String>,
+}
+
+impl ErrorValue {
+    pub fn new(error: &(dyn std::error::Error + 'static)) -> Self {
+        let mut sources = Vec::new();
+        let mut source = error.source();
+        while let Some(err) = source {
+            sources.push(err.to_string());
+            source = err.source();
+        }
+
+        ErrorValue {
+            message: error.to_string(),
+            sources,
+        }
+    }
 }
 
 impl From<u64> for ValueTypes {
     fn from(value: u64) -> Self {
-        ValueTypes::v_u64(value)
+        ValueTypes::U64(value)
     }
 }
 
 impl From<i64> for ValueTypes {
     fn from(value: i64) -> Self {
-        ValueTypes::v_i64(value)
+        ValueTypes::I64(value)
     }
 }
 
 impl From<u128> for ValueTypes {
     fn from(value: u128) -> Self {
-        ValueTypes::v_u128(value)
+        ValueTypes::U128(value)
     }
 }
 
 impl From<i128> for ValueTypes {
     fn from(value: i128) -> Self {
-        ValueTypes::v_i128(value)
+        ValueTypes::I128(value)
     }
 }
 
 impl From<f64> for ValueTypes {
     fn from(value: f64) -> Self {
-        ValueTypes::v_f64(value)
+        ValueTypes::F64(value)
     }
 }
 
 impl From<bool> for ValueTypes {
     fn from(value: bool) -> Self {
-        ValueTypes::v_bool(value)
+        ValueTypes::Bool(value)
     }
 }
 
 impl From<&'static str> for ValueTypes {
     fn from(value: &'static str) -> Self {
-        ValueTypes::v_str(Cow::from(value))
+        ValueTypes::Str(Cow::from(value))
     }
 }
 
 impl From<String> for ValueTypes {
     fn from(value: String) -> Self {
-        ValueTypes::v_str(Cow::from(value))
+        ValueTypes::Str(Cow::from(value))
     }
 }
 
 impl From<char> for ValueTypes {
     fn from(value: char) -> Self {
-        ValueTypes::v_char(value)
+        ValueTypes::Char(value)
+    }
+}
+
+impl From<ErrorValue> for ValueTypes {
+    fn from(value: ErrorValue) -> Self {
+        ValueTypes::Error(Box::new(value))
     }
 }
 
-pub(crate) struct FieldAndValue<'a> {
-    #[allow(dead_code)]
-    pub(crate) field_name: &'static str,
-    #[allow(dead_code)]
-    pub(crate) value: &'a ValueTypes,
+/// A field name and its recorded value.
+pub struct FieldAndValue<'a> {
+    pub field_name: &'static str,
+    pub value: &'a ValueTypes,
 }
 
 #[doc(hidden)]
-#[derive(Default)]
+#[derive(Clone)]
 pub struct FieldValueIndex {
     pub(crate) field: &'static str,
-    pub(crate) value: ValueTypes,
-    pub(crate) sort_index: u8,
+    /// `None` until a value is recorded for the field.
+    pub(crate) value: Option<ValueTypes>,
 }
 
-pub(crate) struct ValueVisitor<'a> {
-    pub(crate) fields: &'a mut [FieldValueIndex],
+/// Create storage for the fields declared by a callsite, with every value unset.
+///
+/// Values are stored in declaration order, so a field's position is its
+/// [`field::Field::index`] and any number of fields can be stored.
+pub(crate) fn new_fields(metadata: &tracing::Metadata<'_>) -> Box<[FieldValueIndex]> {
+    metadata
+        .fields()
+        .iter()
+        .map(|field| FieldValueIndex {
+            field: field.name(),
+            value: None,
+        })
+        .collect()
 }
 
-impl<'a> ValueVisitor<'a> {
-    fn update_value(&mut self, field_name: &'static str, value: ValueTypes) {
-        let res = self.fields.binary_search_by_key(&field_name, |idx| {
-            self.fields[idx.sort_index as usize].field
-        });
-        if let Ok(idx) = res {
-            self.fields[self.fields[idx].sort_index as usize].value = value;
-        } else {
-            // We don't support (and don't need to support) adding new fields that weren't in the original metadata
+/// String buffers longer than this are dropped rather than kept for later events.
+const MAX_REUSED_STRING: usize = 4096;
+
+thread_local! {
+    static EVENT_FIELDS: Cell<EventFields> = Cell::new(EventFields::default());
+}
+
+/// Storage for the fields of the event being written, reused by each thread so that
+/// writing an event does not allocate once the thread has written a few.
+/// Spans keep their fields for their whole life, so they use [`new_fields`] instead.
+#[derive(Default)]
+pub(crate) struct EventFields {
+    pub(crate) fields: Vec<FieldValueIndex>,
+    // Emptied buffers from earlier events, reused for string and `Debug` values
+    strings: Vec<String>,
+}
+
+impl EventFields {
+    /// Run `f` with this thread's storage, holding an unset value for each field of `metadata`.
+    pub(crate) fn with<R>(
+        metadata: &tracing::Metadata<'_>,
+        f: impl FnOnce(&mut EventFields) -> R,
+    ) -> R {
+        // A backend may write an event of its own while this one is written, so the
+        // storage is taken rather than borrowed, and the nested event gets new storage.
+        let mut storage = EVENT_FIELDS.try_with(Cell::take).unwrap_or_default();
+        storage.reset(metadata);
+
+        let result = f(&mut storage);
+
+        let _ = EVENT_FIELDS.try_with(|cell| cell.set(storage));
+        result
+    }
+
+    fn reset(&mut self, metadata: &tracing::Metadata<'_>) {
+        for f in self.fields.drain(..) {
+            if let Some(ValueTypes::Str(Cow::Owned(mut string))) = f.value {
+                if string.capacity() <= MAX_REUSED_STRING {
+                    string.clear();
+                    self.strings.push(string);
+                }
+            }
+        }
+
+        self.fields
+            .extend(metadata.fields().iter().map(|field| FieldValueIndex {
+                field: field.name(),
+                value: None,
+            }));
+    }
+
+    /// A visitor that records into this storage.
+    pub(crate) fn visitor(&mut self) -> ValueVisitor<'_> {
+        ValueVisitor {
+            fields: &mut self.fields,
+            strings: Some(&mut self.strings),
         }
     }
+
+    /// Add a field holding a copy of `value`.
+    pub(crate) fn push(&mut self, field: &'static str, value: &ValueTypes) {
+        let value = match value {
+            ValueTypes::Str(Cow::Owned(string)) => {
+                let mut copy = self.strings.pop().unwrap_or_default();
+                copy.push_str(string);
+                ValueTypes::Str(Cow::Owned(copy))
+            }
+            value => value.clone(),
+        };
+
+        self.fields.push(FieldValueIndex {
+            field,
+            value: Some(value),
+        });
+    }
 }
 
-impl<'a> field::Visit for ValueVisitor<'a> {
-    fn record_debug(&mut self, field: &field::Field, value: &dyn std::fmt::Debug) {
-        let mut string = String::with_capacity(10); // Just a guess
-        if write!(string, "{:?}", value).is_err() {
-            return;
+/// The names `span_name.field_name` for each field declared by a span's callsite,
+/// in declaration order. They are allocated once for each callsite and kept for
+/// the life of the process, so callers should cache the result for each span.
+pub(crate) fn prefixed_field_names(
+    metadata: &'static tracing::Metadata<'static>,
+) -> &'static [&'static str] {
+    type Names = std::collections::HashMap<tracing::callsite::Identifier, &'static [&'static str]>;
+    static NAMES: once_cell::sync::Lazy<std::sync::RwLock<Names>> =
+        once_cell::sync::Lazy::new(Default::default);
+
+    if let Some(names) = NAMES.read().unwrap().get(&metadata.callsite()) {
+        return names;
+    }
+
+    NAMES
+        .write()
+        .unwrap()
+        .entry(metadata.callsite())
+        .or_insert_with(|| {
+            let names: Box<[&'static str]> = metadata
+                .fields()
+                .iter()
+                .map(|field| {
+                    &*Box::leak(format!("{}.{}", metadata.name(), field.name()).into_boxed_str())
+                })
+                .collect();
+            Box::leak(names)
+        })
+}
+
+/// Header values set on a span or event through reserved field names.
+///
+/// The reserved fields are `etw.keyword`, `etw.tag`, `etw.opcode` and `etw.level`.
+/// They are moved out of the field storage as they are found, so they are never
+/// written as payload fields.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct HeaderOverrides {
+    pub(crate) keyword: Option<u64>,
+    pub(crate) tag: Option<u32>,
+    pub(crate) opcode: Option<u8>,
+    pub(crate) level: Option<u8>,
+}
+
+/// The largest event tag the platform's events can carry.
+/// EventHeader tags are 16 bits, and ETW tags 28 bits.
+#[cfg(target_os = "linux")]
+const MAX_EVENT_TAG: u64 = u16::MAX as u64;
+#[cfg(not(target_os = "linux"))]
+const MAX_EVENT_TAG: u64 = 0x0FFF_FFFF;
+
+/// Returns true if the field name is one of the names reserved for header values.
+#[inline]
+pub(crate) fn is_reserved_field(name: &str) -> bool {
+    name.starts_with("etw.")
+        && matches!(name, "etw.keyword" | "etw.tag" | "etw.opcode" | "etw.level")
+}
+
+impl HeaderOverrides {
+    /// Move any recorded reserved fields out of `fields`.
+    /// Values that do not fit the header value they override are dropped. This includes
+    /// `etw.tag` values too wide for the platform's event tags, and `etw.level` values
+    /// outside the levels 1 (critical) to 6, the level `TRACE` spans and events are written at.
+    pub(crate) fn take_from(&mut self, fields: &mut [FieldValueIndex]) {
+        for f in fields.iter_mut() {
+            if f.value.is_none() || !is_reserved_field(f.field) {
+                continue;
+            }
+
+            match f.value.take() {
+                Some(ValueTypes::U64(u)) => self.set(f.field, u),
+                Some(ValueTypes::I64(i)) if i >= 0 => self.set(f.field, i as u64),
+                _ => (),
+            }
         }
+    }
 
-        self.update_value(field.name(), ValueTypes::v_str(Cow::from(string)));
+    fn set(&mut self, field_name: &str, value: u64) {
+        match field_name {
+            "etw.keyword" => self.keyword = Some(value),
+            "etw.tag" => self.tag = (value <= MAX_EVENT_TAG).then_some(value as u32),
+            "etw.opcode" => self.opcode = u8::try_from(value).ok(),
+            "etw.level" => {
+                let levels = 1..=u64::from(crate::map_level(&tracing::Level::TRACE));
+                self.level = levels.contains(&value).then_some(value as u8);
+            }
+            _ => (),
+        }
     }
+}
 
-    fn record_f64(&mut self, field: &field::Field, value: f64) {
-        self.update_value(field.name(), ValueTypes::v_f64(value));
+/// Reads the reserved fields of an event without storing the rest, for filtering.
+impl field::Visit for HeaderOverrides {
+    fn record_debug(&mut self, _field: &field::Field, _value: &dyn std::fmt::Debug) {}
+
+    fn record_u64(&mut self, field: &field::Field, value: u64) {
+        self.set(field.name(), value);
     }
 
     fn record_i64(&mut self, field: &field::Field, value: i64) {
-        self.update_value(field.name(), ValueTypes::v_i64(value));
+        if value >= 0 {
+            self.set(field.name(), value as u64);
+        }
     }
+}
 
-    fn record_u64(&mut self, field: &field::Field, value: u64) {
-        self.update_value(field.name(), ValueTypes::v_u64(value));
+/// The fields of a span or event.
+///
+/// Iterating yields only the fields that have a recorded value, in the order
+/// they were declared.
+#[derive(Clone, Copy)]
+pub struct Fields<'a> {
+    fields: &'a [FieldValueIndex],
+}
+
+impl<'a> Fields<'a> {
+    pub(crate) fn new(fields: &'a [FieldValueIndex]) -> Self {
+        Fields { fields }
     }
 
-    fn record_i128(&mut self, field: &field::Field, value: i128) {
-        self.update_value(field.name(), ValueTypes::v_i128(value));
+    /// The number of fields that have a recorded value.
+    pub fn len(&self) -> usize {
+        self.iter().count()
     }
 
-    fn record_u128(&mut self, field: &field::Field, value: u128) {
-        self.update_value(field.name(), ValueTypes::v_u128(value));
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
     }
 
-    fn record_bool(&mut self, field: &field::Field, value: bool) {
-        self.update_value(field.name(), ValueTypes::v_bool(value));
+    pub fn iter(&self) -> FieldsIter<'a> {
+        FieldsIter {
+            inner: self.fields.iter(),
+        }
     }
+}
 
-    fn record_str(&mut self, field: &field::Field, value: &str) {
-        self.update_value(
-            field.name(),
-            ValueTypes::v_str(Cow::from(value.to_string())),
-        );
+impl<'a> IntoIterator for Fields<'a> {
+    type Item = FieldAndValue<'a>;
+    type IntoIter = FieldsIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
+}
 
-    fn record_error(&mut self, _field: &field::Field, _value: &(dyn std::error::Error + 'static)) {}
+/// Iterator over the recorded values in [`Fields`].
+#[derive(Clone)]
+pub struct FieldsIter<'a> {
+    inner: std::slice::Iter<'a, FieldValueIndex>,
 }
 
-pub(crate) trait AddFieldAndValue<T> {
-    fn add_field_value(&mut self, fv: &crate::values::FieldAndValue);
+impl<'a> Iterator for FieldsIter<'a> {
+    type Item = FieldAndValue<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.find_map(|f| {
+            f.value.as_ref().map(|value| FieldAndValue {
+                field_name: f.field,
+                value,
+            })
+        })
+    }
 }
 
-pub(crate) struct VisitorWrapper<T> {
-    wrapped: T,
+pub(crate) struct ValueVisitor<'a> {
+    fields: &'a mut [FieldValueIndex],
+    // Buffers to reuse for string values, if the fields belong to an event
+    strings: Option<&'a mut Vec<String>>,
 }
 
-impl<T> From<T> for VisitorWrapper<T>
-where
-    T: AddFieldAndValue<T>,
-{
-    fn from(value: T) -> Self {
-        VisitorWrapper { wrapped: value }
+impl<'a> ValueVisitor<'a> {
+    pub(crate) fn new(fields: &'a mut [FieldValueIndex]) -> Self {
+        ValueVisitor {
+            fields,
+            strings: None,
+        }
+    }
+
+    fn string(&mut self) -> String {
+        self.strings
+            .as_mut()
+            .and_then(|strings| strings.pop())
+            .unwrap_or_default()
+    }
+
+    fn update_value(&mut self, field: &field::Field, value: ValueTypes) {
+        if let Some(f) = self.fields.get_mut(field.index()) {
+            f.value = Some(value);
+        } else {
+            // We don't support (and don't need to support) adding new fields that weren't in the original metadata
+        }
     }
 }
 
-impl<T> field::Visit for VisitorWrapper<T>
-where
-    T: AddFieldAndValue<T>,
-{
+impl<'a> field::Visit for ValueVisitor<'a> {
     fn record_debug(&mut self, field: &field::Field, value: &dyn std::fmt::Debug) {
-        let mut string = String::with_capacity(10);
+        let mut string = self.string();
         if write!(string, "{:?}", value).is_err() {
-            // TODO: Needs to do a heap allocation
             return;
         }
 
-        self.wrapped.add_field_value(&FieldAndValue {
-            field_name: field.name(),
-            value: &ValueTypes::from(string),
-        })
+        self.update_value(field, ValueTypes::Str(Cow::from(string)));
     }
 
     fn record_f64(&mut self, field: &field::Field, value: f64) {
-        self.wrapped.add_field_value(&FieldAndValue {
-            field_name: field.name(),
-            value: &ValueTypes::from(value),
-        })
+        self.update_value(field, ValueTypes::F64(value));
     }
 
     fn record_i64(&mut self, field: &field::Field, value: i64) {
-        self.wrapped.add_field_value(&FieldAndValue {
-            field_name: field.name(),
-            value: &ValueTypes::from(value),
-        })
+        self.update_value(field, ValueTypes::I64(value));
     }
 
     fn record_u64(&mut self, field: &field::Field, value: u64) {
-        self.wrapped.add_field_value(&FieldAndValue {
-            field_name: field.name(),
-            value: &ValueTypes::from(value),
-        })
+        self.update_value(field, ValueTypes::U64(value));
     }
 
     fn record_i128(&mut self, field: &field::Field, value: i128) {
-        self.wrapped.add_field_value(&FieldAndValue {
-            field_name: field.name(),
-            value: &ValueTypes::from(value),
-        })
+        self.update_value(field, ValueTypes::I128(value));
     }
 
     fn record_u128(&mut self, field: &field::Field, value: u128) {
-        self.wrapped.add_field_value(&FieldAndValue {
-            field_name: field.name(),
-            value: &ValueTypes::from(value),
-        })
+        self.update_value(field, ValueTypes::U128(value));
     }
 
     fn record_bool(&mut self, field: &field::Field, value: bool) {
-        self.wrapped.add_field_value(&FieldAndValue {
-            field_name: field.name(),
-            value: &ValueTypes::from(value),
-        })
+        self.update_value(field, ValueTypes::Bool(value));
     }
 
     fn record_str(&mut self, field: &field::Field, value: &str) {
-        self.wrapped.add_field_value(&FieldAndValue {
-            field_name: field.name(),
-            value: &ValueTypes::from(value.to_string()),
-        })
+        let mut string = self.string();
+        string.push_str(value);
+        self.update_value(field, ValueTypes::Str(Cow::from(string)));
     }
 
-    fn record_error(&mut self, _field: &field::Field, _value: &(dyn std::error::Error + 'static)) {}
+    fn record_error(&mut self, field: &field::Field, value: &(dyn std::error::Error + 'static)) {
+        self.update_value(field, ErrorValue::new(value).into());
+    }
+}
+
+pub(crate) trait AddFieldAndValue<T> {
+    fn add_field_value(&mut self, fv: &crate::values::FieldAndValue);
 }
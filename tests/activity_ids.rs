@@ -0,0 +1,130 @@
+use tracing_etw::capture::{CapturedEvent, CapturedOpcode, EventCapture};
+use tracing_etw::{ActivityIdGenerator, LayerBuilder, RandomActivityIds, SequentialActivityIds};
+use tracing_subscriber::{prelude::*, Layer, Registry};
+
+/// Run `f` with the layer `build` makes from a capture.
+fn capture<L>(build: impl FnOnce(&EventCapture) -> L, f: impl FnOnce()) -> Vec<CapturedEvent>
+where
+    L: Layer<Registry> + Send + Sync + 'static,
+{
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(build(&capture));
+    tracing::subscriber::with_default(subscriber, f);
+    capture.events()
+}
+
+/// Write a parent span and a child span, each with an event inside.
+fn parent_and_child() {
+    tracing::info_span!("parent").in_scope(|| {
+        tracing::info!(name: "in_parent", "step");
+        tracing::info_span!("child").in_scope(|| {
+            tracing::info!(name: "in_child", "step");
+        });
+    });
+}
+
+fn activity_ids(events: &[CapturedEvent]) -> Vec<(&str, CapturedOpcode, [u8; 16])> {
+    events
+        .iter()
+        .map(|e| (e.name.as_str(), e.opcode, e.activity_id.unwrap()))
+        .collect()
+}
+
+/// Activity IDs that spell out the span's name, padded with zeros.
+struct NamedActivityIds;
+
+impl ActivityIdGenerator for NamedActivityIds {
+    fn activity_id(
+        &self,
+        _span_id: u64,
+        metadata: &'static tracing::Metadata<'static>,
+    ) -> [u8; 16] {
+        let mut activity_id = [0; 16];
+        let name = metadata.name().as_bytes();
+        activity_id[..name.len()].copy_from_slice(name);
+        activity_id
+    }
+}
+
+fn named(name: &str) -> [u8; 16] {
+    let mut activity_id = [0; 16];
+    activity_id[..name.len()].copy_from_slice(name.as_bytes());
+    activity_id
+}
+
+#[test]
+fn custom_generators_choose_each_span_activity_id() {
+    let events = capture(
+        |capture| {
+            LayerBuilder::new_capture("test_provider", capture)
+                .with_activity_ids(NamedActivityIds)
+                .build()
+        },
+        parent_and_child,
+    );
+
+    assert_eq!(
+        activity_ids(&events),
+        [
+            ("parent", CapturedOpcode::Start, named("parent")),
+            ("in_parent", CapturedOpcode::Info, named("parent")),
+            ("child", CapturedOpcode::Start, named("child")),
+            ("in_child", CapturedOpcode::Info, named("child")),
+            ("child", CapturedOpcode::Stop, named("child")),
+            ("parent", CapturedOpcode::Stop, named("parent")),
+        ]
+    );
+
+    // Children are related to their parent's activity
+    let child_start = &events[2];
+    assert_eq!(child_start.related_activity_id, Some(named("parent")));
+    assert_eq!(events[0].related_activity_id, None);
+}
+
+#[test]
+fn sequential_activity_ids_count_up_from_one() {
+    let events = capture(
+        |capture| {
+            LayerBuilder::new_capture("test_provider", capture)
+                .with_activity_ids(SequentialActivityIds::new())
+                .build()
+        },
+        parent_and_child,
+    );
+
+    let starts: Vec<_> = events
+        .iter()
+        .filter(|e| e.opcode == CapturedOpcode::Start)
+        .map(|e| e.activity_id.unwrap())
+        .collect();
+    assert_eq!(starts, [1u128.to_be_bytes(), 2u128.to_be_bytes()]);
+}
+
+#[test]
+fn random_activity_ids_are_v4_guids_unique_across_span_id_reuse() {
+    let events = capture(
+        |capture| {
+            LayerBuilder::new_capture("test_provider", capture)
+                .with_activity_ids(RandomActivityIds)
+                .build()
+        },
+        || {
+            // The registry reuses the ID of a closed span for the next one
+            for _ in 0..3 {
+                tracing::info_span!("work").in_scope(|| {});
+            }
+        },
+    );
+
+    let starts: Vec<_> = events
+        .iter()
+        .filter(|e| e.opcode == CapturedOpcode::Start)
+        .map(|e| e.activity_id.unwrap())
+        .collect();
+    assert_eq!(starts.len(), 3);
+    for (i, activity_id) in starts.iter().enumerate() {
+        assert_eq!(activity_id[7] >> 4, 4);
+        assert_eq!(activity_id[8] >> 6, 0b10);
+        assert!(!starts[i + 1..].contains(activity_id));
+    }
+}
@@ -0,0 +1,82 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use tracing_etw::{EventRecord, EventWriter, LayerBuilder, SpanRecord};
+use tracing_subscriber::prelude::*;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+struct NullSession;
+
+impl EventWriter for NullSession {
+    fn enabled(&self, _level: u8, _keyword: u64) -> bool {
+        true
+    }
+
+    fn supports_enable_callback() -> bool {
+        false
+    }
+
+    fn span_start(self: Pin<&Self>, _span: &SpanRecord<'_>, _timestamp: SystemTime) {}
+
+    fn span_stop(
+        self: Pin<&Self>,
+        _span: &SpanRecord<'_>,
+        _start_stop_times: (SystemTime, SystemTime),
+    ) {
+    }
+
+    fn write_record(self: Pin<&Self>, _event: &EventRecord<'_>) {}
+}
+
+#[test]
+fn events_reuse_field_storage() {
+    let layer = LayerBuilder::with_backend("test_provider", NullSession).build();
+    let subscriber = tracing_subscriber::registry().with(layer);
+
+    tracing::subscriber::with_default(subscriber, || {
+        let write = |i: u32| {
+            tracing::info!(
+                text = "some text that is not short",
+                debug = ?(i, "a tuple"),
+                count = i,
+                "an event"
+            );
+        };
+
+        // The first events on a thread allocate the storage that later events reuse
+        for i in 0..4 {
+            write(i);
+        }
+
+        let before = allocations();
+        for i in 0..100 {
+            write(i);
+        }
+        assert_eq!(allocations(), before);
+    });
+}
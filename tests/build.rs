@@ -0,0 +1,38 @@
+use tracing_etw::capture::EventCapture;
+use tracing_etw::{BuildError, LayerBuilder};
+use tracing_subscriber::Registry;
+
+#[test]
+fn build_accepts_names_that_try_build_rejects_on_linux() {
+    for name in ["MyCompany.MyApp", "my-app"] {
+        let _layer = LayerBuilder::new(name).build::<Registry>();
+
+        let result = LayerBuilder::new(name).try_build::<Registry>();
+        if cfg!(target_os = "linux") {
+            assert_eq!(
+                result.err(),
+                Some(BuildError::InvalidProviderName(name.to_owned()))
+            );
+        }
+    }
+}
+
+#[test]
+fn custom_backends_accept_any_provider_name() {
+    let capture = EventCapture::new();
+
+    for name in ["my.provider", "my-app"] {
+        let result = LayerBuilder::new_capture(name, &capture).try_build::<Registry>();
+        assert!(result.is_ok(), "{:?}", name);
+    }
+}
+
+#[test]
+fn try_build_rejects_a_zero_keyword() {
+    let capture = EventCapture::new();
+
+    let result = LayerBuilder::new_capture("myapp", &capture)
+        .with_default_keyword(0)
+        .try_build::<Registry>();
+    assert_eq!(result.err(), Some(BuildError::ZeroKeyword));
+}
@@ -0,0 +1,211 @@
+use tracing_etw::{BuildError, ConfigError, EtwConfig, ProviderConfig};
+use tracing_subscriber::Registry;
+
+fn into_builder_error(spec: &str) -> Option<ConfigError> {
+    spec.parse::<ProviderConfig>()
+        .and_then(ProviderConfig::into_builder)
+        .err()
+}
+
+#[test]
+fn into_builder_rejects_specs_that_parse_but_cannot_be_built() {
+    assert_eq!(
+        into_builder_error("myapp;keyword=0"),
+        Some(ConfigError::Build {
+            provider: "myapp".to_owned(),
+            error: BuildError::ZeroKeyword,
+        })
+    );
+    assert_eq!(
+        into_builder_error("myapp;id=00000000-0000-0000-0000-000000000000"),
+        Some(ConfigError::Build {
+            provider: "myapp".to_owned(),
+            error: BuildError::ZeroProviderId,
+        })
+    );
+
+    let mut config = ProviderConfig::new("myapp");
+    config.target_keywords.insert("myapp::db".to_owned(), 0);
+    assert_eq!(
+        config.into_builder().err(),
+        Some(ConfigError::Build {
+            provider: "myapp".to_owned(),
+            error: BuildError::ZeroKeyword,
+        })
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn into_builder_rejects_invalid_names_and_groups() {
+    assert_eq!(
+        into_builder_error("my-app"),
+        Some(ConfigError::Build {
+            provider: "my-app".to_owned(),
+            error: BuildError::InvalidProviderName("my-app".to_owned()),
+        })
+    );
+    assert_eq!(
+        into_builder_error("myapp;group=my group"),
+        Some(ConfigError::Build {
+            provider: "myapp".to_owned(),
+            error: BuildError::InvalidGroupName("my group".to_owned()),
+        })
+    );
+}
+
+#[test]
+fn into_builder_accepts_a_valid_spec() {
+    assert_eq!(
+        into_builder_error("myapp;keyword=0x10;targets=myapp=info"),
+        None
+    );
+}
+
+#[test]
+fn etw_config_build_reports_invalid_providers() {
+    let mut invalid = ProviderConfig::new("second");
+    invalid.default_keyword = Some(0);
+    let config = EtwConfig::new(vec![ProviderConfig::new("first"), invalid]);
+
+    assert_eq!(
+        config.build::<Registry>().err(),
+        Some(ConfigError::Build {
+            provider: "second".to_owned(),
+            error: BuildError::ZeroKeyword,
+        })
+    );
+}
+
+fn parse_error(spec: &str) -> ConfigError {
+    spec.parse::<ProviderConfig>().unwrap_err()
+}
+
+fn invalid_value(option: &str, value: &str) -> ConfigError {
+    ConfigError::InvalidValue {
+        option: option.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+#[test]
+fn parse_rejects_malformed_specs() {
+    assert_eq!(parse_error(""), ConfigError::MissingProviderName);
+    assert_eq!(
+        parse_error(" ;keyword=0x10"),
+        ConfigError::MissingProviderName
+    );
+    assert_eq!(
+        parse_error("myapp;level=info"),
+        ConfigError::UnknownOption("level".to_owned())
+    );
+    assert_eq!(
+        parse_error("myapp;keyword=0xzz"),
+        invalid_value("keyword", "0xzz")
+    );
+    assert_eq!(
+        parse_error("myapp;keyword=-1"),
+        invalid_value("keyword", "-1")
+    );
+    assert_eq!(
+        parse_error("myapp;id=not-a-guid"),
+        invalid_value("id", "not-a-guid")
+    );
+    assert_eq!(
+        parse_error("myapp;common_schema=yes"),
+        invalid_value("common_schema", "yes")
+    );
+    assert_eq!(
+        parse_error("myapp;targets=myapp=loud"),
+        invalid_value("targets", "myapp=loud")
+    );
+}
+
+#[test]
+fn parse_reads_every_option() {
+    let config: ProviderConfig =
+        "myapp; id=6f9619ff-8b86-d011-b42d-00c04fc964ff; group=grp; keyword=0x10; common_schema=true; targets=myapp=info,myapp::db=trace"
+            .parse()
+            .unwrap();
+
+    assert_eq!(config.name, "myapp");
+    assert!(config.provider_id.is_some());
+    assert_eq!(config.group.as_deref(), Some("grp"));
+    assert_eq!(config.default_keyword, Some(0x10));
+    assert!(config.common_schema);
+    assert_eq!(
+        config.targets.as_deref(),
+        Some("myapp=info,myapp::db=trace")
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn deserializes_the_same_config_as_the_spec_strings() {
+    let json = r#"{
+        "providers": [
+            {
+                "name": "myapp",
+                "id": "6f9619ff-8b86-d011-b42d-00c04fc964ff",
+                "keyword": 16,
+                "keywords": { "myapp::db": 4 },
+                "targets": "myapp=info"
+            },
+            { "name": "myapp_requests", "common_schema": true, "group": "mygroup" }
+        ]
+    }"#;
+
+    let mut myapp: ProviderConfig =
+        "myapp;id=6f9619ff-8b86-d011-b42d-00c04fc964ff;keyword=0x10;targets=myapp=info"
+            .parse()
+            .unwrap();
+    myapp.target_keywords.insert("myapp::db".to_owned(), 4);
+    let requests = "myapp_requests;group=mygroup;common_schema=true"
+        .parse()
+        .unwrap();
+
+    let config: EtwConfig = serde_json::from_str(json).unwrap();
+    assert_eq!(config, EtwConfig::new(vec![myapp, requests]));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_round_trips_the_config() {
+    let mut full: ProviderConfig =
+        "myapp;id=6f9619ff-8b86-d011-b42d-00c04fc964ff;group=mygroup;keyword=0x10;common_schema=true;targets=myapp=info,myapp::db=trace"
+            .parse()
+            .unwrap();
+    full.target_keywords.insert("myapp::db".to_owned(), 4);
+    let config = EtwConfig::new(vec![full, ProviderConfig::new("other")]);
+
+    let json = serde_json::to_string(&config).unwrap();
+    assert_eq!(serde_json::from_str::<EtwConfig>(&json).unwrap(), config);
+
+    // Unset options are left out rather than written as null
+    let minimal = serde_json::to_value(ProviderConfig::new("other")).unwrap();
+    assert_eq!(
+        minimal,
+        serde_json::json!({
+            "name": "other",
+            "keywords": {},
+            "common_schema": false
+        })
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn deserializing_rejects_unknown_options_and_invalid_ids() {
+    assert!(serde_json::from_str::<ProviderConfig>(r#"{ "name": "myapp", "level": 4 }"#).is_err());
+
+    let error =
+        serde_json::from_str::<ProviderConfig>(r#"{ "name": "myapp", "id": "not-a-guid" }"#)
+            .unwrap_err();
+    assert!(error.to_string().contains(
+        &ConfigError::InvalidValue {
+            option: "id".to_owned(),
+            value: "not-a-guid".to_owned(),
+        }
+        .to_string()
+    ));
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+
+use tracing_etw::capture::{CapturedOpcode, CapturedValue, EventCapture};
+use tracing_etw::{ErrorValue, LayerBuilder};
+use tracing_subscriber::prelude::*;
+
+/// An error with an optional source, to build a chain from.
+#[derive(Debug)]
+struct ChainedError {
+    message: &'static str,
+    source: Option<Box<ChainedError>>,
+}
+
+impl ChainedError {
+    fn chain(messages: &[&'static str]) -> Self {
+        let (message, sources) = messages.split_first().unwrap();
+        ChainedError {
+            message,
+            source: (!sources.is_empty()).then(|| Box::new(ChainedError::chain(sources))),
+        }
+    }
+}
+
+impl fmt::Display for ChainedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message)
+    }
+}
+
+impl std::error::Error for ChainedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn std::error::Error + 'static))
+    }
+}
+
+#[test]
+fn errors_are_recorded_with_their_source_chain() {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry()
+        .with(LayerBuilder::new_capture("test_provider", &capture).build());
+
+    tracing::subscriber::with_default(subscriber, || {
+        let error = ChainedError::chain(&["request failed", "connection reset", "timed out"]);
+        let error = &error as &(dyn std::error::Error + 'static);
+        tracing::info_span!("request", error).in_scope(|| {
+            tracing::error!(name: "failed", error);
+        });
+    });
+
+    let expected = CapturedValue::Error(ErrorValue {
+        message: "request failed".to_owned(),
+        sources: vec!["connection reset".to_owned(), "timed out".to_owned()],
+    });
+
+    let events = capture.events();
+    let start = events
+        .iter()
+        .find(|e| e.opcode == CapturedOpcode::Start)
+        .unwrap();
+    assert_eq!(start.field("error"), Some(&expected));
+
+    let event = events.iter().find(|e| e.name == "failed").unwrap();
+    assert_eq!(event.field("error"), Some(&expected));
+}
+
+#[test]
+fn errors_without_a_source_have_an_empty_chain() {
+    let error = ChainedError::chain(&["not found"]);
+
+    assert_eq!(
+        ErrorValue::new(&error),
+        ErrorValue {
+            message: "not found".to_owned(),
+            sources: Vec::new(),
+        }
+    );
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use tracing_etw::capture::EventCapture;
+use tracing_etw::{EventNaming, LayerBuilder};
+use tracing_subscriber::prelude::*;
+
+/// The names of the span and events written with the given naming policy.
+fn names(naming: EventNaming) -> Vec<String> {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(
+        LayerBuilder::new_capture("test_provider", &capture)
+            .with_event_naming(naming)
+            .build(),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info_span!(target: "myapp::db", "query").in_scope(|| {
+            tracing::info!(target: "myapp::db", name = "pool_ready", "connected");
+            tracing::info!(target: "myapp::db", rows = 3u64);
+        });
+    });
+
+    capture.events().into_iter().map(|e| e.name).collect()
+}
+
+fn is_callsite_name(name: &str) -> bool {
+    name.starts_with("event tests") && name.contains("event_naming.rs:")
+}
+
+#[test]
+fn callsite_names_are_the_default() {
+    let names = names(EventNaming::default());
+
+    assert_eq!(names[0], "query");
+    assert!(is_callsite_name(&names[1]));
+    assert!(is_callsite_name(&names[2]));
+    assert_ne!(names[1], names[2]);
+    assert_eq!(names[3], "query");
+}
+
+#[test]
+fn events_can_be_named_by_target() {
+    assert_eq!(
+        names(EventNaming::Target),
+        ["query", "myapp::db", "myapp::db", "query"]
+    );
+}
+
+#[test]
+fn events_can_be_named_by_target_and_message() {
+    assert_eq!(
+        names(EventNaming::TargetAndMessage),
+        ["query", "myapp::db: connected", "myapp::db", "query"]
+    );
+}
+
+#[test]
+fn events_can_be_named_by_a_field() {
+    let names = names(EventNaming::Field("name"));
+
+    assert_eq!(names[1], "pool_ready");
+    // Events without the field keep their callsite name
+    assert!(is_callsite_name(&names[2]));
+    assert_eq!(names[3], "query");
+}
+
+#[test]
+fn events_can_be_named_by_a_closure() {
+    let naming = EventNaming::Custom(Arc::new(|metadata| {
+        format!(
+            "{}.{}",
+            metadata.target().replace("::", "."),
+            metadata.level()
+        )
+    }));
+
+    assert_eq!(
+        names(naming),
+        ["query", "myapp.db.INFO", "myapp.db.INFO", "query"]
+    );
+}
@@ -0,0 +1,123 @@
+use tracing_etw::capture::{CapturedEvent, CapturedOpcode, CapturedValue, EventCapture};
+use tracing_etw::LayerBuilder;
+use tracing_subscriber::prelude::*;
+
+fn capture(f: impl FnOnce()) -> Vec<CapturedEvent> {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry()
+        .with(LayerBuilder::new_capture("test_provider", &capture).build());
+    tracing::subscriber::with_default(subscriber, f);
+    capture.events()
+}
+
+/// Check the event has the fields `f00` to `f35` in order, each holding its number.
+fn assert_numbered_fields(event: &CapturedEvent) {
+    let fields: Vec<_> = event
+        .fields
+        .iter()
+        .map(|f| (f.name.to_owned(), f.value.clone()))
+        .collect();
+    let expected: Vec<_> = (0..36u64)
+        .map(|i| (format!("f{:02}", i), CapturedValue::U64(i)))
+        .collect();
+    assert_eq!(fields, expected);
+}
+
+#[test]
+fn spans_and_events_write_more_than_32_fields_in_declaration_order() {
+    let events = capture(|| {
+        let span = tracing::info_span!(
+            "wide",
+            f00 = tracing::field::Empty,
+            f01 = 1u64,
+            f02 = tracing::field::Empty,
+            f03 = 3u64,
+            f04 = tracing::field::Empty,
+            f05 = 5u64,
+            f06 = tracing::field::Empty,
+            f07 = 7u64,
+            f08 = tracing::field::Empty,
+            f09 = 9u64,
+            f10 = tracing::field::Empty,
+            f11 = 11u64,
+            f12 = tracing::field::Empty,
+            f13 = 13u64,
+            f14 = tracing::field::Empty,
+            f15 = 15u64,
+            f16 = tracing::field::Empty,
+            f17 = 17u64,
+            f18 = tracing::field::Empty,
+            f19 = 19u64,
+            f20 = tracing::field::Empty,
+            f21 = 21u64,
+            f22 = tracing::field::Empty,
+            f23 = 23u64,
+            f24 = tracing::field::Empty,
+            f25 = 25u64,
+            f26 = tracing::field::Empty,
+            f27 = 27u64,
+            f28 = tracing::field::Empty,
+            f29 = 29u64,
+            f30 = tracing::field::Empty,
+            f31 = 31u64,
+            f32 = tracing::field::Empty,
+            f33 = 33u64,
+            f34 = tracing::field::Empty,
+            f35 = 35u64
+        );
+        // Record the empty fields last to first
+        for i in (0..36u32).step_by(2).rev() {
+            span.record(format!("f{:02}", i).as_str(), u64::from(i));
+        }
+        let _enter = span.enter();
+
+        tracing::info!(
+            name: "wide_event",
+            f00 = 0u64,
+            f01 = 1u64,
+            f02 = 2u64,
+            f03 = 3u64,
+            f04 = 4u64,
+            f05 = 5u64,
+            f06 = 6u64,
+            f07 = 7u64,
+            f08 = 8u64,
+            f09 = 9u64,
+            f10 = 10u64,
+            f11 = 11u64,
+            f12 = 12u64,
+            f13 = 13u64,
+            f14 = 14u64,
+            f15 = 15u64,
+            f16 = 16u64,
+            f17 = 17u64,
+            f18 = 18u64,
+            f19 = 19u64,
+            f20 = 20u64,
+            f21 = 21u64,
+            f22 = 22u64,
+            f23 = 23u64,
+            f24 = 24u64,
+            f25 = 25u64,
+            f26 = 26u64,
+            f27 = 27u64,
+            f28 = 28u64,
+            f29 = 29u64,
+            f30 = 30u64,
+            f31 = 31u64,
+            f32 = 32u64,
+            f33 = 33u64,
+            f34 = 34u64,
+            f35 = 35u64
+        );
+    });
+
+    let start = events
+        .iter()
+        .find(|e| e.opcode == CapturedOpcode::Start)
+        .unwrap();
+    assert_numbered_fields(start);
+
+    let event = events.iter().find(|e| e.name == "wide_event").unwrap();
+    assert_numbered_fields(event);
+}
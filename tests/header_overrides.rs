@@ -0,0 +1,178 @@
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use tracing::Level;
+use tracing_etw::capture::{CapturedEvent, CapturedOpcode, EventCapture};
+use tracing_etw::{EventRecord, EventWriter, LayerBuilder, SpanMode, SpanRecord};
+use tracing_subscriber::{prelude::*, Layer, Registry};
+
+/// Run `f` with the layer `build` makes from a capture.
+fn capture<L>(build: impl FnOnce(&EventCapture) -> L, f: impl FnOnce()) -> Vec<CapturedEvent>
+where
+    L: Layer<Registry> + Send + Sync + 'static,
+{
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(build(&capture));
+    tracing::subscriber::with_default(subscriber, f);
+    capture.events()
+}
+
+fn has_reserved_fields(event: &CapturedEvent) -> bool {
+    event.fields.iter().any(|f| f.name.starts_with("etw."))
+}
+
+#[test]
+fn keyword_override_needs_a_declared_keyword() {
+    let events = capture(
+        |capture| {
+            LayerBuilder::new_capture("test_provider", capture)
+                .with_target_keyword("app::db", 0x4)
+                .with_override_keyword(0x100)
+                .build()
+        },
+        || {
+            tracing::event!(target: "app", Level::INFO, etw.keyword = 0x100u64, "declared");
+            tracing::event!(target: "app", Level::INFO, etw.keyword = 0x4u64, "target keyword");
+            tracing::event!(target: "app", Level::INFO, etw.keyword = 0x200u64, "undeclared");
+            tracing::event!(target: "app::db", Level::INFO, etw.keyword = 0x200u64, "undeclared");
+            tracing::event!(target: "app", Level::INFO, etw.keyword = -1i64, "negative");
+        },
+    );
+
+    let keywords: Vec<u64> = events.iter().map(|e| e.keyword).collect();
+    assert_eq!(keywords, [0x100, 0x4, 0x1, 0x4, 0x1]);
+    assert!(!events.iter().any(has_reserved_fields));
+}
+
+#[test]
+fn tag_override_must_fit_the_platform() {
+    let max_tag: u64 = if cfg!(target_os = "linux") {
+        0xFFFF
+    } else {
+        0x0FFF_FFFF
+    };
+
+    let events = capture(
+        |capture| LayerBuilder::new_capture("test_provider", capture).build(),
+        || {
+            tracing::info!(etw.tag = 0x10u64, "small");
+            tracing::info!(etw.tag = max_tag, "largest");
+            tracing::info!(etw.tag = max_tag + 1, "too wide");
+            tracing::info!(etw.tag = 70000u64, "too wide for user_events");
+            tracing::info!(etw.tag = -1i64, "negative");
+        },
+    );
+
+    let tags: Vec<u64> = events.iter().map(|e| e.event_tag.into()).collect();
+    let expected_70000 = if cfg!(target_os = "linux") { 0 } else { 70000 };
+    assert_eq!(tags, [0x10, max_tag, 0, expected_70000, 0]);
+    assert!(!events.iter().any(has_reserved_fields));
+}
+
+#[test]
+fn level_override_accepts_the_levels_the_layer_writes() {
+    let events = capture(
+        |capture| LayerBuilder::new_capture("test_provider", capture).build(),
+        || {
+            tracing::event!(Level::INFO, etw.level = 1u64, "critical");
+            tracing::event!(Level::ERROR, etw.level = 5u64, "verbose");
+            tracing::event!(Level::INFO, etw.level = 6u64, "trace");
+            tracing::event!(Level::INFO, etw.level = 0u64, "log always");
+            tracing::event!(Level::INFO, etw.level = 7u64, "too high");
+        },
+    );
+
+    let levels: Vec<u8> = events.iter().map(|e| e.level).collect();
+    assert_eq!(levels, [1, 5, 6, 4, 4]);
+    assert!(!events.iter().any(has_reserved_fields));
+}
+
+/// A capture that only writes events with the given keywords, like a session that enabled them.
+struct KeywordSession {
+    capture: EventCapture,
+    keywords: u64,
+}
+
+impl EventWriter for KeywordSession {
+    fn enabled(&self, _level: u8, keyword: u64) -> bool {
+        keyword & self.keywords != 0
+    }
+
+    fn supports_enable_callback() -> bool {
+        false
+    }
+
+    fn span_start(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        Pin::new(&self.capture).span_start(span, timestamp);
+    }
+
+    fn span_stop(
+        self: Pin<&Self>,
+        span: &SpanRecord<'_>,
+        start_stop_times: (SystemTime, SystemTime),
+    ) {
+        Pin::new(&self.capture).span_stop(span, start_stop_times);
+    }
+
+    fn write_record(self: Pin<&Self>, event: &EventRecord<'_>) {
+        Pin::new(&self.capture).write_record(event);
+    }
+}
+
+#[test]
+fn span_overrides_are_checked_against_the_session() {
+    for mode in [SpanMode::PerEnter, SpanMode::Lifecycle, SpanMode::Async] {
+        let events = capture(
+            |capture| {
+                LayerBuilder::with_backend(
+                    "test_provider",
+                    KeywordSession {
+                        capture: capture.clone(),
+                        keywords: 0x1,
+                    },
+                )
+                .with_override_keyword(0x100)
+                .with_span_mode(mode)
+                .build()
+            },
+            || {
+                tracing::info_span!("disabled", etw.keyword = 0x100u64).in_scope(|| {
+                    tracing::info!(name: "inside", "inside");
+                });
+                tracing::info_span!("enabled", etw.keyword = 0x1u64).in_scope(|| {});
+            },
+        );
+
+        let names: Vec<&str> = events.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["inside", "enabled", "enabled"], "{:?}", mode);
+        assert!(!events.iter().any(has_reserved_fields));
+    }
+}
+
+#[test]
+fn opcode_override_only_applies_to_events() {
+    let events = capture(
+        |capture| LayerBuilder::new_capture("test_provider", capture).build(),
+        || {
+            tracing::info!(etw.opcode = 11u64, "custom");
+            tracing::info!(etw.opcode = 255u64, "largest");
+            tracing::info!(etw.opcode = 256u64, "too wide");
+            tracing::info_span!("span", etw.opcode = 11u64, etw.tag = 0x20u64).in_scope(|| {});
+        },
+    );
+
+    let opcodes: Vec<CapturedOpcode> = events.iter().map(|e| e.opcode).collect();
+    assert_eq!(
+        opcodes,
+        [
+            CapturedOpcode::Other(11),
+            CapturedOpcode::Other(255),
+            CapturedOpcode::Info,
+            CapturedOpcode::Start,
+            CapturedOpcode::Stop,
+        ]
+    );
+    // Spans still take their other overrides
+    assert_eq!(events[3].event_tag, 0x20);
+    assert!(!events.iter().any(has_reserved_fields));
+}
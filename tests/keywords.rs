@@ -0,0 +1,110 @@
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use tracing_etw::capture::{CapturedEvent, CapturedOpcode, EventCapture};
+use tracing_etw::{EventRecord, EventWriter, LayerBuilder, SpanRecord};
+use tracing_subscriber::{prelude::*, Layer, Registry};
+
+/// Run `f` with the layer `build` makes from a capture.
+fn capture<L>(build: impl FnOnce(&EventCapture) -> L, f: impl FnOnce()) -> Vec<CapturedEvent>
+where
+    L: Layer<Registry> + Send + Sync + 'static,
+{
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(build(&capture));
+    tracing::subscriber::with_default(subscriber, f);
+    capture.events()
+}
+
+/// Write a span and an event from each of several targets.
+fn subsystems() {
+    tracing::info_span!(target: "myapp::db", "query").in_scope(|| {
+        tracing::info!(name: "db", target: "myapp::db::pool", "connected");
+    });
+    tracing::info!(name: "http", target: "myapp::http", "listening");
+    tracing::info!(name: "other", target: "other", "started");
+}
+
+/// A capture whose session only enables some keywords, as a consumer of one subsystem would.
+struct KeywordSession {
+    capture: EventCapture,
+    keywords: u64,
+}
+
+impl EventWriter for KeywordSession {
+    fn enabled(&self, _level: u8, keyword: u64) -> bool {
+        keyword & self.keywords != 0
+    }
+
+    fn supports_enable_callback() -> bool {
+        false
+    }
+
+    fn span_start(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        Pin::new(&self.capture).span_start(span, timestamp);
+    }
+
+    fn span_stop(
+        self: Pin<&Self>,
+        span: &SpanRecord<'_>,
+        start_stop_times: (SystemTime, SystemTime),
+    ) {
+        Pin::new(&self.capture).span_stop(span, start_stop_times);
+    }
+
+    fn write_record(self: Pin<&Self>, event: &EventRecord<'_>) {
+        Pin::new(&self.capture).write_record(event);
+    }
+}
+
+#[test]
+fn the_longest_matching_target_prefix_chooses_the_keyword() {
+    let events = capture(
+        |capture| {
+            LayerBuilder::new_capture("test_provider", capture)
+                .with_default_keyword(0x1)
+                .with_target_keyword("myapp::db", 0x4)
+                .with_target_keyword("myapp", 0x2)
+                .build()
+        },
+        subsystems,
+    );
+
+    let keywords: Vec<_> = events
+        .iter()
+        .map(|e| (e.name.as_str(), e.opcode, e.keyword))
+        .collect();
+    assert_eq!(
+        keywords,
+        [
+            ("query", CapturedOpcode::Start, 0x4),
+            ("db", CapturedOpcode::Info, 0x4),
+            ("query", CapturedOpcode::Stop, 0x4),
+            ("http", CapturedOpcode::Info, 0x2),
+            ("other", CapturedOpcode::Info, 0x1),
+        ]
+    );
+}
+
+#[test]
+fn sessions_can_enable_a_single_subsystem() {
+    let events = capture(
+        |capture| {
+            LayerBuilder::with_backend(
+                "test_provider",
+                KeywordSession {
+                    capture: capture.clone(),
+                    keywords: 0x4,
+                },
+            )
+            .with_default_keyword(0x1)
+            .with_target_keyword("myapp::db", 0x4)
+            .with_target_keyword("myapp", 0x2)
+            .build()
+        },
+        subsystems,
+    );
+
+    let names: Vec<_> = events.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, ["query", "db", "query"]);
+}
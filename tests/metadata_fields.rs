@@ -0,0 +1,111 @@
+use tracing_etw::capture::{CapturedEvent, CapturedOpcode, CapturedValue, EventCapture};
+use tracing_etw::LayerBuilder;
+use tracing_subscriber::{prelude::*, Layer, Registry};
+
+/// Run `f` with the layer `build` makes from a capture.
+fn capture<L>(build: impl FnOnce(&EventCapture) -> L, f: impl FnOnce()) -> Vec<CapturedEvent>
+where
+    L: Layer<Registry> + Send + Sync + 'static,
+{
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(build(&capture));
+    tracing::subscriber::with_default(subscriber, f);
+    capture.events()
+}
+
+fn field_names(event: &CapturedEvent) -> Vec<&str> {
+    event.fields.iter().map(|f| f.name).collect()
+}
+
+/// Write a span with an event inside it, and return the event's line number.
+fn span_and_event() -> u32 {
+    let _span = tracing::info_span!("work", id = 1u64).entered();
+    tracing::info!(rows = 3u64);
+    line!() - 1
+}
+
+#[test]
+fn metadata_fields_are_written_before_the_payload() {
+    let mut line = 0;
+    let events = capture(
+        |capture| {
+            LayerBuilder::new_capture("test_provider", capture)
+                .with_file(true)
+                .with_line_number(true)
+                .with_module_path(true)
+                .with_target(true)
+                .build()
+        },
+        || line = span_and_event(),
+    );
+
+    let event = events
+        .iter()
+        .find(|e| e.opcode == CapturedOpcode::Info)
+        .unwrap();
+    assert_eq!(
+        field_names(event),
+        ["file", "line", "module_path", "target", "rows"]
+    );
+    assert_eq!(
+        event.field("file"),
+        Some(&CapturedValue::Str(file!().to_owned()))
+    );
+    assert_eq!(event.field("line"), Some(&CapturedValue::U64(line.into())));
+    assert_eq!(
+        event.field("module_path"),
+        Some(&CapturedValue::Str(module_path!().to_owned()))
+    );
+    assert_eq!(
+        event.field("target"),
+        Some(&CapturedValue::Str(module_path!().to_owned()))
+    );
+
+    // Only span start events carry the metadata
+    let start = events
+        .iter()
+        .find(|e| e.opcode == CapturedOpcode::Start)
+        .unwrap();
+    assert_eq!(
+        field_names(start),
+        ["file", "line", "module_path", "target", "id"]
+    );
+    let stop = events
+        .iter()
+        .find(|e| e.opcode == CapturedOpcode::Stop)
+        .unwrap();
+    assert_eq!(field_names(stop), ["id"]);
+}
+
+#[test]
+fn metadata_fields_are_chosen_separately() {
+    let events = capture(
+        |capture| {
+            LayerBuilder::new_capture("test_provider", capture)
+                .with_line_number(true)
+                .build()
+        },
+        || {
+            span_and_event();
+        },
+    );
+
+    let event = events
+        .iter()
+        .find(|e| e.opcode == CapturedOpcode::Info)
+        .unwrap();
+    assert_eq!(field_names(event), ["line", "rows"]);
+}
+
+#[test]
+fn metadata_fields_are_not_written_by_default() {
+    let events = capture(
+        |capture| LayerBuilder::new_capture("test_provider", capture).build(),
+        || {
+            span_and_event();
+        },
+    );
+
+    let names: Vec<_> = events.iter().map(field_names).collect();
+    assert_eq!(names, [vec!["id"], vec!["rows"], vec!["id"]]);
+}
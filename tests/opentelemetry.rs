@@ -0,0 +1,78 @@
+#![cfg(feature = "opentelemetry")]
+
+use opentelemetry::trace::{SpanContext, TraceContextExt, TracerProvider as _};
+use tracing_etw::capture::{CapturedOpcode, EventCapture};
+use tracing_etw::LayerBuilder;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::prelude::*;
+
+fn span_context(span: &tracing::Span) -> SpanContext {
+    span.context().span().span_context().clone()
+}
+
+#[test]
+fn opentelemetry_span_contexts_become_the_trace_context_and_activity_ids() {
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder().build();
+    let capture = EventCapture::new();
+
+    // The OpenTelemetry layer must see each span first
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+        .with(LayerBuilder::new_capture("test_provider", &capture).build());
+
+    let (root, child) = tracing::subscriber::with_default(subscriber, || {
+        let root = tracing::info_span!("root");
+        let _root = root.enter();
+        let child = tracing::info_span!("child");
+        let _child = child.enter();
+        tracing::info!(name: "inside", "step");
+        (span_context(&root), span_context(&child))
+    });
+
+    let events = capture.events();
+    let start = |name: &str| {
+        events
+            .iter()
+            .find(|e| e.opcode == CapturedOpcode::Start && e.name == name)
+            .unwrap()
+    };
+    let trace_id = root.trace_id().to_bytes();
+
+    let root_start = start("root");
+    let root_context = root_start.trace_context.unwrap();
+    assert_eq!(root_context.trace_id, trace_id);
+    assert_eq!(root_context.span_id, root.span_id().to_bytes());
+    assert_eq!(root_context.parent_span_id, None);
+    assert!(root_context.sampled);
+
+    let child_start = start("child");
+    let child_context = child_start.trace_context.unwrap();
+    assert_eq!(child_context.trace_id, trace_id);
+    assert_eq!(child_context.span_id, child.span_id().to_bytes());
+    assert_eq!(
+        child_context.parent_span_id,
+        Some(root.span_id().to_bytes())
+    );
+
+    // Activity IDs are the first half of the trace ID followed by the span ID
+    let activity_id = |span_id: [u8; 8]| {
+        let mut activity_id = [0; 16];
+        activity_id[..8].copy_from_slice(&trace_id[..8]);
+        activity_id[8..].copy_from_slice(&span_id);
+        activity_id
+    };
+    assert_eq!(
+        root_start.activity_id,
+        Some(activity_id(root.span_id().to_bytes()))
+    );
+    assert_eq!(
+        child_start.activity_id,
+        Some(activity_id(child.span_id().to_bytes()))
+    );
+    assert_eq!(child_start.related_activity_id, root_start.activity_id);
+
+    // Events take the context of the span they occur in
+    let inside = events.iter().find(|e| e.name == "inside").unwrap();
+    assert_eq!(inside.trace_context, Some(child_context));
+    assert_eq!(inside.activity_id, child_start.activity_id);
+}
@@ -0,0 +1,102 @@
+use tracing_etw::capture::{CapturedEvent, EventCapture};
+use tracing_etw::LayerBuilder;
+use tracing_subscriber::prelude::*;
+
+const TRACE_ID: [u8; 16] = [
+    0x4b, 0xf9, 0x2f, 0x35, 0x77, 0xb3, 0x4d, 0xa6, 0xa3, 0xce, 0x92, 0x9d, 0x0e, 0x0e, 0x47, 0x36,
+];
+const SPAN_ID: [u8; 8] = [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7];
+
+/// The start event of a span that imports `traceparent`.
+fn import(traceparent: &str) -> CapturedEvent {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry()
+        .with(LayerBuilder::new_capture("test_provider", &capture).build());
+
+    tracing::subscriber::with_default(subscriber, || {
+        let _span = tracing::info_span!("receive", etw.traceparent = traceparent).entered();
+    });
+
+    capture.events().remove(0)
+}
+
+/// Whether the span adopted the trace and parent span of `traceparent`.
+fn imported(traceparent: &str) -> bool {
+    let trace_context = import(traceparent).trace_context.unwrap();
+    trace_context.trace_id == TRACE_ID && trace_context.parent_span_id == Some(SPAN_ID)
+}
+
+#[test]
+fn traceparent_is_imported() {
+    let start = import("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+    let trace_context = start.trace_context.unwrap();
+
+    assert_eq!(trace_context.trace_id, TRACE_ID);
+    assert_eq!(trace_context.parent_span_id, Some(SPAN_ID));
+    assert_ne!(trace_context.span_id, SPAN_ID);
+    assert!(trace_context.sampled);
+    assert_eq!(start.field("etw.traceparent"), None);
+}
+
+#[test]
+fn imported_span_never_reuses_the_remote_parent_id() {
+    // Both processes number their first span 1 in the registry
+    let trace_context = import("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000001-01")
+        .trace_context
+        .unwrap();
+
+    assert_eq!(trace_context.parent_span_id, Some(1u64.to_be_bytes()));
+    assert_ne!(trace_context.span_id, 1u64.to_be_bytes());
+}
+
+#[test]
+fn traceparent_flags_set_sampled() {
+    let trace_context = import("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00")
+        .trace_context
+        .unwrap();
+
+    assert_eq!(trace_context.trace_id, TRACE_ID);
+    assert!(!trace_context.sampled);
+}
+
+#[test]
+fn traceparent_surrounding_whitespace_is_ignored() {
+    assert!(imported(
+        " 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01\n"
+    ));
+}
+
+#[test]
+fn traceparent_later_versions_may_add_fields() {
+    assert!(imported(
+        "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra"
+    ));
+    assert!(!imported(
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra"
+    ));
+}
+
+#[test]
+fn traceparent_invalid_values_are_dropped() {
+    for traceparent in [
+        "",
+        "00",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1",
+        "0-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+    ] {
+        let start = import(traceparent);
+        let trace_context = start.trace_context.unwrap();
+
+        assert_ne!(trace_context.trace_id, TRACE_ID, "{:?}", traceparent);
+        assert_eq!(trace_context.parent_span_id, None, "{:?}", traceparent);
+        assert!(trace_context.sampled, "{:?}", traceparent);
+        assert_eq!(start.field("etw.traceparent"), None, "{:?}", traceparent);
+    }
+}
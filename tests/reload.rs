@@ -0,0 +1,92 @@
+#![cfg(not(feature = "global_filter"))]
+
+use tracing::{event, Level};
+use tracing_etw::capture::{CapturedValue, EventCapture};
+use tracing_etw::{BuildError, LayerBuilder, ReloadHandle};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::prelude::*;
+
+/// Run `f` with a reloadable capture layer named "test_provider".
+/// Returns the `n` field and keyword of every event written.
+fn with_reload(f: impl FnOnce(&ReloadHandle)) -> Vec<(u64, u64)> {
+    let capture = EventCapture::new();
+    let (layer, handle) = LayerBuilder::new_capture("test_provider", &capture).build_with_reload();
+    let subscriber = tracing_subscriber::registry().with(layer);
+
+    tracing::subscriber::with_default(subscriber, || f(&handle));
+
+    capture
+        .events()
+        .iter()
+        .map(|e| match e.field("n") {
+            Some(CapturedValue::U64(n)) => (*n, e.keyword),
+            other => panic!("n is {:?}", other),
+        })
+        .collect()
+}
+
+#[test]
+fn set_targets_filters_and_keeps_the_provider_name() {
+    let events = with_reload(|handle| {
+        handle.set_targets(Some(Targets::new().with_target("a", LevelFilter::INFO)));
+        event!(target: "a", Level::DEBUG, n = 1u64);
+        event!(target: "a", Level::INFO, n = 2u64);
+        event!(target: "b", Level::ERROR, n = 3u64);
+        event!(target: "test_provider", Level::TRACE, n = 4u64);
+
+        handle.set_targets(None);
+        event!(target: "b", Level::TRACE, n = 5u64);
+    });
+
+    assert_eq!(events, [(2, 1), (4, 1), (5, 1)]);
+}
+
+#[test]
+fn set_max_level_applies_to_every_target() {
+    let events = with_reload(|handle| {
+        handle.set_max_level(LevelFilter::WARN);
+        event!(target: "a", Level::INFO, n = 1u64);
+        event!(target: "test_provider", Level::INFO, n = 2u64);
+        event!(target: "a", Level::WARN, n = 3u64);
+
+        handle.set_max_level(LevelFilter::TRACE);
+        event!(target: "a", Level::TRACE, n = 4u64);
+    });
+
+    assert_eq!(events, [(3, 1), (4, 1)]);
+}
+
+#[test]
+fn set_keywords_changes_later_events() {
+    let events = with_reload(|handle| {
+        event!(target: "a::db", Level::INFO, n = 1u64);
+
+        handle.set_default_keyword(0x4).unwrap();
+        handle
+            .set_target_keywords(&[("a::db".to_owned(), 0x8)])
+            .unwrap();
+        event!(target: "a", Level::INFO, n = 2u64);
+        event!(target: "a::db", Level::INFO, n = 3u64);
+
+        // Replacing the default keyword keeps the target keywords
+        handle.set_default_keyword(0x10).unwrap();
+        event!(target: "a", Level::INFO, n = 4u64);
+        event!(target: "a::db", Level::INFO, n = 5u64);
+    });
+
+    assert_eq!(events, [(1, 1), (2, 0x4), (3, 0x8), (4, 0x10), (5, 0x8)]);
+}
+
+#[test]
+fn zero_keywords_are_rejected() {
+    let events = with_reload(|handle| {
+        assert_eq!(handle.set_default_keyword(0), Err(BuildError::ZeroKeyword));
+        assert_eq!(
+            handle.set_target_keywords(&[("a".to_owned(), 0)]),
+            Err(BuildError::ZeroKeyword)
+        );
+        event!(target: "a", Level::INFO, n = 1u64);
+    });
+
+    assert_eq!(events, [(1, 1)]);
+}
@@ -0,0 +1,71 @@
+use std::sync::{Arc, Mutex};
+
+use tracing_etw::capture::{CapturedEvent, EventCapture};
+use tracing_etw::{LayerBuilder, ProviderRoute, RoutedProvider};
+use tracing_subscriber::prelude::*;
+
+fn names(events: &[CapturedEvent]) -> Vec<&str> {
+    events.iter().map(|e| e.name.as_str()).collect()
+}
+
+#[test]
+fn routes_send_each_callsite_to_one_provider() {
+    let default = EventCapture::new();
+    let audit = EventCapture::new();
+    let billing = EventCapture::new();
+
+    // The names of the callsites the closure was asked about
+    let matched = Arc::new(Mutex::new(Vec::new()));
+    let route = {
+        let matched = matched.clone();
+        ProviderRoute::Custom(Arc::new(move |metadata| {
+            matched.lock().unwrap().push(metadata.name());
+            metadata.name().starts_with("billing")
+        }))
+    };
+
+    let subscriber = tracing_subscriber::registry().with(
+        LayerBuilder::new_capture("default_provider", &default)
+            .with_route(
+                ProviderRoute::Target("audit".to_owned()),
+                RoutedProvider::new_capture("audit_provider", &audit),
+            )
+            .with_route(
+                route,
+                RoutedProvider::new_capture("billing_provider", &billing),
+            )
+            .build(),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        for _ in 0..3 {
+            tracing::info_span!(target: "audit", "audit_span").in_scope(|| {
+                tracing::info!(name: "audit_event", target: "audit", "audited");
+            });
+            tracing::info_span!("billing_span").in_scope(|| {
+                tracing::info!(name: "billing_event", "billed");
+            });
+            tracing::info!(name: "other_event", "neither");
+        }
+    });
+
+    let audit = audit.events();
+    assert_eq!(audit.len(), 9);
+    assert!(names(&audit)
+        .iter()
+        .all(|name| *name == "audit_span" || *name == "audit_event"));
+
+    let billing = billing.events();
+    assert_eq!(billing.len(), 9);
+    assert!(names(&billing)
+        .iter()
+        .all(|name| *name == "billing_span" || *name == "billing_event"));
+
+    assert_eq!(names(&default.events()), ["other_event"; 3]);
+
+    // The target route matched the audit callsites first, and each other
+    // callsite was only checked against the closure once
+    let mut matched = matched.lock().unwrap().clone();
+    matched.sort_unstable();
+    assert_eq!(matched, ["billing_event", "billing_span", "other_event"]);
+}
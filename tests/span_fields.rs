@@ -0,0 +1,87 @@
+use tracing_etw::capture::{CapturedEvent, CapturedValue, EventCapture};
+use tracing_etw::{LayerBuilder, SpanFieldPropagation};
+use tracing_subscriber::prelude::*;
+
+/// Run `f` with a layer that copies span fields onto events as `propagation` says.
+fn capture(propagation: SpanFieldPropagation, f: impl FnOnce()) -> Vec<CapturedEvent> {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(
+        LayerBuilder::new_capture("test_provider", &capture)
+            .with_span_fields(propagation)
+            .build(),
+    );
+    tracing::subscriber::with_default(subscriber, f);
+    capture.events()
+}
+
+/// Write an event inside `request{id=42, user="alice"}` and its child `query{id=7, rows}`.
+fn nested_event() {
+    tracing::info_span!("request", id = 42u64, user = "alice").in_scope(|| {
+        tracing::info_span!("query", id = 7u64, rows = tracing::field::Empty).in_scope(|| {
+            tracing::info!(name: "step", id = 1u64);
+        });
+    });
+}
+
+fn fields(events: &[CapturedEvent]) -> Vec<(&str, CapturedValue)> {
+    let step = events.iter().find(|e| e.name == "step").unwrap();
+    step.fields
+        .iter()
+        .map(|f| (f.name, f.value.clone()))
+        .collect()
+}
+
+#[test]
+fn span_fields_are_not_copied_by_default() {
+    let events = capture(SpanFieldPropagation::None, nested_event);
+
+    assert_eq!(fields(&events), [("id", CapturedValue::U64(1))]);
+}
+
+#[test]
+fn current_span_fields_are_copied_with_the_span_name_prefix() {
+    let events = capture(SpanFieldPropagation::CurrentSpan, nested_event);
+
+    // Fields the span has not recorded yet are skipped
+    assert_eq!(
+        fields(&events),
+        [
+            ("id", CapturedValue::U64(1)),
+            ("query.id", CapturedValue::U64(7)),
+        ]
+    );
+}
+
+#[test]
+fn full_scope_copies_fields_from_the_closest_span_first() {
+    let events = capture(SpanFieldPropagation::FullScope, nested_event);
+
+    assert_eq!(
+        fields(&events),
+        [
+            ("id", CapturedValue::U64(1)),
+            ("query.id", CapturedValue::U64(7)),
+            ("request.id", CapturedValue::U64(42)),
+            ("request.user", CapturedValue::Str("alice".to_owned())),
+        ]
+    );
+}
+
+#[test]
+fn full_scope_keeps_the_closest_value_of_a_repeated_name() {
+    let events = capture(SpanFieldPropagation::FullScope, || {
+        tracing::info_span!("work", depth = 1u64).in_scope(|| {
+            tracing::info_span!("work", depth = 2u64).in_scope(|| {
+                tracing::info!(name: "step", id = 1u64);
+            });
+        });
+    });
+
+    assert_eq!(
+        fields(&events),
+        [
+            ("id", CapturedValue::U64(1)),
+            ("work.depth", CapturedValue::U64(2)),
+        ]
+    );
+}
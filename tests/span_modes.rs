@@ -0,0 +1,225 @@
+use std::time::Duration;
+
+use tracing::{event, span, Level};
+use tracing_etw::capture::{CapturedEvent, CapturedOpcode, CapturedValue, EventCapture};
+use tracing_etw::{LayerBuilder, SpanMode};
+use tracing_subscriber::prelude::*;
+
+fn opcodes(events: &[CapturedEvent]) -> Vec<CapturedOpcode> {
+    events.iter().map(|e| e.opcode).collect()
+}
+
+fn u64_field(event: &CapturedEvent, name: &str) -> u64 {
+    match event.field(name) {
+        Some(CapturedValue::U64(u)) => *u,
+        other => panic!("{} is {:?}", name, other),
+    }
+}
+
+/// Enter and exit a span twice, writing an event each time, then close it.
+fn enter_twice() {
+    let span = span!(Level::INFO, "work", id = 7u64);
+    for _ in 0..2 {
+        let _enter = span.enter();
+        event!(Level::INFO, "step");
+    }
+}
+
+/// Run `f` with a single layer in the given span mode.
+fn single(mode: SpanMode, f: impl FnOnce()) -> Vec<CapturedEvent> {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(
+        LayerBuilder::new_capture("single", &capture)
+            .with_span_mode(mode)
+            .build(),
+    );
+    tracing::subscriber::with_default(subscriber, f);
+    capture.events()
+}
+
+/// Run `f` with two layers in the given span mode stacked in one subscriber.
+fn stacked(mode: SpanMode, f: impl FnOnce()) -> (Vec<CapturedEvent>, Vec<CapturedEvent>) {
+    let first = EventCapture::new();
+    let second = EventCapture::new();
+    let subscriber = tracing_subscriber::registry()
+        .with(
+            LayerBuilder::new_capture("first", &first)
+                .with_span_mode(mode)
+                .build(),
+        )
+        .with(
+            LayerBuilder::new_capture("second", &second)
+                .with_span_mode(mode)
+                .build(),
+        );
+    tracing::subscriber::with_default(subscriber, f);
+    (first.events(), second.events())
+}
+
+#[test]
+fn per_enter_writes_start_and_stop_for_each_enter() {
+    let events = single(SpanMode::PerEnter, enter_twice);
+
+    assert_eq!(
+        opcodes(&events),
+        [
+            CapturedOpcode::Start,
+            CapturedOpcode::Info,
+            CapturedOpcode::Stop,
+            CapturedOpcode::Start,
+            CapturedOpcode::Info,
+            CapturedOpcode::Stop,
+        ]
+    );
+    assert!(events
+        .iter()
+        .all(|e| e.activity_id == events[0].activity_id));
+    assert_eq!(events[0].field("id"), Some(&CapturedValue::U64(7)));
+    assert_eq!(events[2].field("busy_ns"), None);
+}
+
+#[test]
+fn lifecycle_writes_one_start_and_stop() {
+    let events = single(SpanMode::Lifecycle, enter_twice);
+
+    assert_eq!(
+        opcodes(&events),
+        [
+            CapturedOpcode::Start,
+            CapturedOpcode::Info,
+            CapturedOpcode::Info,
+            CapturedOpcode::Stop,
+        ]
+    );
+    assert_eq!(events[3].activity_id, events[0].activity_id);
+    assert_eq!(events[3].field("id"), Some(&CapturedValue::U64(7)));
+    assert!(u64_field(&events[3], "busy_ns") > 0);
+}
+
+#[test]
+fn lifecycle_writes_spans_that_are_never_entered() {
+    let events = single(SpanMode::Lifecycle, || {
+        let _span = span!(Level::INFO, "idle");
+    });
+
+    assert_eq!(
+        opcodes(&events),
+        [CapturedOpcode::Start, CapturedOpcode::Stop]
+    );
+    assert_eq!(u64_field(&events[1], "busy_ns"), 0);
+}
+
+#[test]
+fn async_writes_suspend_and_resume_between_enters() {
+    let events = single(SpanMode::Async, enter_twice);
+
+    assert_eq!(
+        opcodes(&events),
+        [
+            CapturedOpcode::Start,
+            CapturedOpcode::Info,
+            CapturedOpcode::Suspend,
+            CapturedOpcode::Resume,
+            CapturedOpcode::Info,
+            CapturedOpcode::Suspend,
+            CapturedOpcode::Stop,
+        ]
+    );
+    assert!(events
+        .iter()
+        .all(|e| e.activity_id == events[0].activity_id));
+    assert!(u64_field(&events[6], "busy_ns") > 0);
+    assert!(events[6].field("idle_ns").is_some());
+}
+
+#[test]
+fn async_skips_spans_that_are_never_entered() {
+    let events = single(SpanMode::Async, || {
+        let _span = span!(Level::INFO, "idle");
+    });
+
+    assert!(events.is_empty());
+}
+
+#[test]
+fn stacked_per_enter_layers_each_write_start_and_stop() {
+    let (first, second) = stacked(SpanMode::PerEnter, enter_twice);
+
+    for events in [&first, &second] {
+        assert_eq!(
+            opcodes(events),
+            [
+                CapturedOpcode::Start,
+                CapturedOpcode::Info,
+                CapturedOpcode::Stop,
+                CapturedOpcode::Start,
+                CapturedOpcode::Info,
+                CapturedOpcode::Stop,
+            ]
+        );
+        assert_eq!(events[0].field("id"), Some(&CapturedValue::U64(7)));
+    }
+
+    assert_eq!(first[0].activity_id, second[0].activity_id);
+}
+
+#[test]
+fn stacked_lifecycle_layers_each_write_start_and_stop() {
+    let (first, second) = stacked(SpanMode::Lifecycle, enter_twice);
+
+    for events in [&first, &second] {
+        assert_eq!(
+            opcodes(events),
+            [
+                CapturedOpcode::Start,
+                CapturedOpcode::Info,
+                CapturedOpcode::Info,
+                CapturedOpcode::Stop,
+            ]
+        );
+        assert_eq!(events[3].field("id"), Some(&CapturedValue::U64(7)));
+        assert!(u64_field(&events[3], "busy_ns") > 0);
+    }
+
+    assert_eq!(first[0].activity_id, second[0].activity_id);
+}
+
+#[test]
+fn stacked_async_layers_each_track_their_own_state() {
+    let (first, second) = stacked(SpanMode::Async, || {
+        let span = span!(Level::INFO, "work", id = 7u64);
+        for _ in 0..2 {
+            let _enter = span.enter();
+            event!(Level::INFO, "step");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    });
+
+    for events in [&first, &second] {
+        assert_eq!(
+            opcodes(events),
+            [
+                CapturedOpcode::Start,
+                CapturedOpcode::Info,
+                CapturedOpcode::Suspend,
+                CapturedOpcode::Resume,
+                CapturedOpcode::Info,
+                CapturedOpcode::Suspend,
+                CapturedOpcode::Stop,
+            ]
+        );
+        assert_eq!(events[6].field("id"), Some(&CapturedValue::U64(7)));
+    }
+
+    // Each layer times the span itself, so neither counts the other's transitions
+    for events in [&first, &second] {
+        let busy = Duration::from_nanos(u64_field(&events[6], "busy_ns"));
+        let idle = Duration::from_nanos(u64_field(&events[6], "idle_ns"));
+        let elapsed = events[6]
+            .timestamp
+            .duration_since(events[0].timestamp)
+            .unwrap();
+        assert!(busy >= Duration::from_millis(20));
+        assert!(busy + idle <= elapsed + Duration::from_millis(5));
+    }
+}
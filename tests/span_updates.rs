@@ -0,0 +1,83 @@
+use tracing_etw::capture::{CapturedEvent, CapturedOpcode, CapturedValue, EventCapture};
+use tracing_etw::LayerBuilder;
+use tracing_subscriber::prelude::*;
+
+/// Run `f` with a layer that does or does not write span update events.
+fn capture(update_events: bool, f: impl FnOnce()) -> Vec<CapturedEvent> {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(
+        LayerBuilder::new_capture("test_provider", &capture)
+            .with_span_update_events(update_events)
+            .build(),
+    );
+    tracing::subscriber::with_default(subscriber, f);
+    capture.events()
+}
+
+/// Record `rows` and then `status` on an open span.
+fn record_late_values() {
+    let span = tracing::info_span!(
+        "query",
+        table = "users",
+        rows = tracing::field::Empty,
+        status = tracing::field::Empty
+    );
+    let _enter = span.enter();
+    span.record("rows", 10u64);
+    span.record("status", "ok");
+}
+
+fn fields(event: &CapturedEvent) -> Vec<(&str, CapturedValue)> {
+    event
+        .fields
+        .iter()
+        .map(|f| (f.name, f.value.clone()))
+        .collect()
+}
+
+#[test]
+fn record_writes_an_update_event_with_the_new_values() {
+    let events = capture(true, record_late_values);
+
+    let opcodes: Vec<_> = events.iter().map(|e| e.opcode).collect();
+    assert_eq!(
+        opcodes,
+        [
+            CapturedOpcode::Start,
+            CapturedOpcode::Info,
+            CapturedOpcode::Info,
+            CapturedOpcode::Stop,
+        ]
+    );
+
+    let start = &events[0];
+    for update in &events[1..3] {
+        assert_eq!(update.name, "query");
+        assert_eq!(update.activity_id, start.activity_id);
+        assert_eq!(update.related_activity_id, start.related_activity_id);
+    }
+
+    // Each update only carries the fields that were just recorded
+    assert_eq!(fields(&events[1]), [("rows", CapturedValue::U64(10))]);
+    assert_eq!(
+        fields(&events[2]),
+        [("status", CapturedValue::Str("ok".to_owned()))]
+    );
+}
+
+#[test]
+fn record_only_updates_the_stop_event_by_default() {
+    let events = capture(false, record_late_values);
+
+    let opcodes: Vec<_> = events.iter().map(|e| e.opcode).collect();
+    assert_eq!(opcodes, [CapturedOpcode::Start, CapturedOpcode::Stop]);
+
+    assert_eq!(
+        fields(&events[1]),
+        [
+            ("table", CapturedValue::Str("users".to_owned())),
+            ("rows", CapturedValue::U64(10)),
+            ("status", CapturedValue::Str("ok".to_owned())),
+        ]
+    );
+}
@@ -0,0 +1,126 @@
+#![cfg(not(feature = "global_filter"))]
+
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use tracing_etw::capture::{CapturedEvent, EventCapture};
+use tracing_etw::{EventRecord, EventWriter, LayerBuilder, SpanRecord};
+use tracing_subscriber::{prelude::*, Layer, Registry};
+
+/// Run `f` with the layer `build` makes from a capture.
+fn capture<L>(build: impl FnOnce(&EventCapture) -> L, f: impl FnOnce()) -> Vec<CapturedEvent>
+where
+    L: Layer<Registry> + Send + Sync + 'static,
+{
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(build(&capture));
+    tracing::subscriber::with_default(subscriber, f);
+    capture.events()
+}
+
+/// Write an event at each level from several targets, named `target level`.
+fn events() {
+    tracing::trace!(name: "myapp trace", target: "myapp", "");
+    tracing::info!(name: "myapp info", target: "myapp", "");
+    tracing::trace!(name: "myapp::db trace", target: "myapp::db", "");
+    tracing::debug!(name: "myapp::db debug", target: "myapp::db", "");
+    tracing::info!(name: "hyper info", target: "hyper", "");
+    tracing::warn!(name: "hyper warn", target: "hyper", "");
+    tracing::error!(name: "other error", target: "other", "");
+    tracing::trace!(name: "test_provider trace", target: "test_provider", "");
+}
+
+fn names(events: &[CapturedEvent]) -> Vec<&str> {
+    events.iter().map(|e| e.name.as_str()).collect()
+}
+
+/// A capture whose session only enables levels up to and including info.
+struct InfoSession {
+    capture: EventCapture,
+}
+
+impl EventWriter for InfoSession {
+    fn enabled(&self, level: u8, _keyword: u64) -> bool {
+        level <= 4
+    }
+
+    fn supports_enable_callback() -> bool {
+        false
+    }
+
+    fn span_start(self: Pin<&Self>, span: &SpanRecord<'_>, timestamp: SystemTime) {
+        Pin::new(&self.capture).span_start(span, timestamp);
+    }
+
+    fn span_stop(
+        self: Pin<&Self>,
+        span: &SpanRecord<'_>,
+        start_stop_times: (SystemTime, SystemTime),
+    ) {
+        Pin::new(&self.capture).span_stop(span, start_stop_times);
+    }
+
+    fn write_record(self: Pin<&Self>, event: &EventRecord<'_>) {
+        Pin::new(&self.capture).write_record(event);
+    }
+}
+
+#[test]
+fn build_with_targets_applies_each_directive() {
+    let events = capture(
+        |capture| {
+            LayerBuilder::new_capture("test_provider", capture)
+                .build_with_targets("myapp=info,myapp::db=trace,hyper=warn".parse().unwrap())
+        },
+        events,
+    );
+
+    // The provider's own name is always enabled
+    assert_eq!(
+        names(&events),
+        [
+            "myapp info",
+            "myapp::db trace",
+            "myapp::db debug",
+            "hyper warn",
+            "test_provider trace",
+        ]
+    );
+}
+
+#[test]
+fn build_with_targets_still_asks_the_session() {
+    let events = capture(
+        |capture| {
+            LayerBuilder::with_backend(
+                "test_provider",
+                InfoSession {
+                    capture: capture.clone(),
+                },
+            )
+            .build_with_targets("myapp=info,myapp::db=trace,hyper=warn".parse().unwrap())
+        },
+        events,
+    );
+
+    assert_eq!(names(&events), ["myapp info", "hyper warn"]);
+}
+
+#[test]
+fn build_with_target_enables_every_level_of_one_target() {
+    let events = capture(
+        |capture| LayerBuilder::new_capture("test_provider", capture).build_with_target("myapp"),
+        events,
+    );
+
+    assert_eq!(
+        names(&events),
+        [
+            "myapp trace",
+            "myapp info",
+            "myapp::db trace",
+            "myapp::db debug",
+            "test_provider trace",
+        ]
+    );
+}
@@ -0,0 +1,94 @@
+use tracing_etw::capture::{CapturedEvent, CapturedValue, EventCapture};
+use tracing_etw::LayerBuilder;
+use tracing_subscriber::prelude::*;
+
+/// Write an event with every thread field from each of `threads` threads.
+fn capture_from_threads(threads: usize) -> Vec<CapturedEvent> {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(
+        LayerBuilder::new_capture("test_provider", &capture)
+            .with_thread_id(true)
+            .with_thread_name(true)
+            .with_process_id(true)
+            .build(),
+    );
+    let dispatch = tracing::Dispatch::new(subscriber);
+
+    for i in 0..threads {
+        let dispatch = dispatch.clone();
+        std::thread::Builder::new()
+            .name(format!("worker{}", i))
+            .spawn(move || tracing::dispatcher::with_default(&dispatch, || tracing::info!("hi")))
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    capture.events()
+}
+
+fn u64_field(event: &CapturedEvent, name: &str) -> u64 {
+    match event.field(name) {
+        Some(CapturedValue::U64(u)) => *u,
+        other => panic!("{} is {:?}", name, other),
+    }
+}
+
+#[test]
+fn thread_fields_describe_the_writing_thread() {
+    let events = capture_from_threads(2);
+
+    assert_eq!(
+        events[0].field("thread_name"),
+        Some(&CapturedValue::Str("worker0".to_owned()))
+    );
+    assert_eq!(
+        events[1].field("thread_name"),
+        Some(&CapturedValue::Str("worker1".to_owned()))
+    );
+    assert_ne!(
+        u64_field(&events[0], "thread_id"),
+        u64_field(&events[1], "thread_id")
+    );
+    for event in &events {
+        assert_eq!(u64_field(event, "process_id"), std::process::id() as u64);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn forked_child_writes_its_own_ids() {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(
+        LayerBuilder::new_capture("test_provider", &capture)
+            .with_thread_id(true)
+            .with_process_id(true)
+            .build(),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        // Cache the parent's IDs on this thread
+        tracing::info!("parent");
+        let parent = capture.take().remove(0);
+
+        match unsafe { libc::fork() } {
+            0 => {
+                tracing::info!("child");
+                let child = capture.take().remove(0);
+                let pid = unsafe { libc::getpid() } as u64;
+                let tid = unsafe { libc::syscall(libc::SYS_gettid) } as u64;
+                let ok = u64_field(&child, "process_id") == pid
+                    && u64_field(&child, "thread_id") == tid
+                    && u64_field(&parent, "process_id") != pid;
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+            }
+            -1 => panic!("fork failed"),
+            child => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+            }
+        }
+    });
+}
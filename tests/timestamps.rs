@@ -0,0 +1,68 @@
+use std::time::SystemTime;
+
+use tracing_etw::capture::{CapturedEvent, EventCapture};
+use tracing_etw::{LayerBuilder, TimestampClock};
+use tracing_subscriber::prelude::*;
+
+/// Write a span with an event inside it, using the given timestamp clock.
+fn capture(clock: TimestampClock) -> Vec<CapturedEvent> {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry().with(
+        LayerBuilder::new_capture("test_provider", &capture)
+            .with_timestamp_clock(clock)
+            .build(),
+    );
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info_span!("work").in_scope(|| {
+            tracing::info!("step");
+        });
+    });
+    capture.events()
+}
+
+#[cfg(target_os = "linux")]
+fn clock_monotonic() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    assert_eq!(
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) },
+        0
+    );
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[test]
+fn wall_clock_timestamps_are_read_as_events_happen() {
+    for clock in [TimestampClock::WallClock, TimestampClock::Omit] {
+        let before = SystemTime::now();
+        let events = capture(clock);
+        let after = SystemTime::now();
+
+        assert_eq!(events.len(), 3);
+        for pair in events.windows(2) {
+            assert!(pair[0].timestamp <= pair[1].timestamp);
+        }
+        for event in &events {
+            assert!(before <= event.timestamp && event.timestamp <= after);
+            assert_eq!(event.monotonic_timestamp, None);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn monotonic_timestamps_read_clock_monotonic() {
+    let before = clock_monotonic();
+    let events = capture(TimestampClock::Monotonic);
+    let after = clock_monotonic();
+
+    let timestamps: Vec<u64> = events
+        .iter()
+        .map(|e| e.monotonic_timestamp.unwrap())
+        .collect();
+    assert_eq!(timestamps.len(), 3);
+    assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(before <= timestamps[0] && timestamps[2] <= after);
+}
@@ -0,0 +1,76 @@
+use tracing_etw::capture::{CapturedOpcode, EventCapture};
+use tracing_etw::LayerBuilder;
+use tracing_subscriber::prelude::*;
+
+#[test]
+fn spans_get_random_span_ids() {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry()
+        .with(LayerBuilder::new_capture("test_provider", &capture).build());
+
+    tracing::subscriber::with_default(subscriber, || {
+        // The registry reuses the ID of a closed span for the next one
+        for _ in 0..2 {
+            let _span = tracing::info_span!("root").entered();
+            let _child = tracing::info_span!("child").entered();
+        }
+    });
+
+    let starts: Vec<_> = capture
+        .events()
+        .iter()
+        .filter(|e| e.opcode == CapturedOpcode::Start)
+        .map(|e| e.trace_context.unwrap())
+        .collect();
+    assert_eq!(starts.len(), 4);
+
+    for (i, context) in starts.iter().enumerate() {
+        assert_ne!(context.span_id, [0; 8]);
+        assert_ne!(context.span_id, 1u64.to_be_bytes());
+        for other in &starts[i + 1..] {
+            assert_ne!(context.span_id, other.span_id);
+        }
+    }
+
+    // Children keep their parent's trace, and each root starts a new one
+    assert_eq!(starts[1].trace_id, starts[0].trace_id);
+    assert_eq!(starts[1].parent_span_id, Some(starts[0].span_id));
+    assert_ne!(starts[2].trace_id, starts[0].trace_id);
+    assert_eq!(starts[2].parent_span_id, None);
+}
+
+#[test]
+fn follows_from_writes_a_receive_event_related_to_the_followed_span() {
+    let capture = EventCapture::new();
+    let subscriber = tracing_subscriber::registry()
+        .with(LayerBuilder::new_capture("test_provider", &capture).build());
+
+    tracing::subscriber::with_default(subscriber, || {
+        let cause = tracing::info_span!("cause");
+        cause.in_scope(|| {});
+
+        let effect = tracing::info_span!("effect");
+        effect.in_scope(|| {});
+        effect.follows_from(&cause);
+    });
+
+    let events = capture.events();
+    let start = |name: &str| {
+        events
+            .iter()
+            .find(|e| e.opcode == CapturedOpcode::Start && e.name == name)
+            .unwrap()
+    };
+    let cause = start("cause");
+    let effect = start("effect");
+
+    let receive: Vec<_> = events
+        .iter()
+        .filter(|e| e.opcode == CapturedOpcode::Receive)
+        .collect();
+    assert_eq!(receive.len(), 1);
+    assert_eq!(receive[0].name, "effect");
+    assert_eq!(receive[0].activity_id, effect.activity_id);
+    assert_eq!(receive[0].related_activity_id, cause.activity_id);
+    assert_ne!(cause.activity_id, effect.activity_id);
+}